@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{executor::LocalPool, AsyncWriteExt};
+use giphy_proxy::{buffer_pool::BufferPool, tunnel};
+use http::test_support::TestTransport;
+
+/// Enough short lived tunnels per iteration for buffer allocation to show up.
+const TUNNELS: usize = 1000;
+
+/// Each tunnel carries about one small request and response.
+const MESSAGE: &[u8] = b"GET /v1/gifs/trending HTTP/1.1\r\nHost: api.giphy.com\r\n\r\n";
+
+async fn short_lived_tunnel(buffers: &BufferPool) {
+    let (mut client, proxy_side) = TestTransport::pair();
+    let (upstream_side, mut remote) = TestTransport::pair();
+
+    client.write_all(MESSAGE).await.unwrap();
+    client.close().await.unwrap();
+    remote.write_all(MESSAGE).await.unwrap();
+    remote.close().await.unwrap();
+
+    tunnel(proxy_side, upstream_side, None, buffers).await;
+}
+
+fn bench_tunnel_buffers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tunnel");
+
+    group.throughput(Throughput::Elements(TUNNELS as u64));
+
+    // With nothing pooled, every copy loop allocates its own buffer as it did before pooling.
+    for (name, max_pooled) in &[("allocate_per_tunnel", 0), ("pooled_buffers", 256)] {
+        group.bench_function(*name, |b| {
+            let buffers = BufferPool::new(1024, *max_pooled);
+            let mut pool = LocalPool::new();
+
+            b.iter(|| {
+                pool.run_until(async {
+                    for _ in 0..TUNNELS {
+                        short_lived_tunnel(&buffers).await;
+                    }
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_tunnel_buffers);
+criterion_main!(benches);
@@ -0,0 +1,106 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Hands out fixed size buffers and takes them back when they're dropped, so copy loops on short
+/// lived tunnels don't allocate a fresh buffer each time. Keeps at most max_pooled buffers around;
+/// past that, returned buffers are freed. A max of 0 allocates on every get.
+pub struct BufferPool {
+    buffer_len: usize,
+    max_pooled: usize,
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new(buffer_len: usize, max_pooled: usize) -> Self {
+        Self {
+            buffer_len,
+            max_pooled,
+            buffers: Mutex::new(vec![]),
+        }
+    }
+
+    /// A buffer of buffer_len bytes, reused if one is free. Its contents are whatever the last
+    /// user left behind.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buffer = self.buffers.lock().unwrap().pop().unwrap_or_else(|| vec![0; self.buffer_len]);
+
+        PooledBuffer {
+            pool: self,
+            buffer,
+        }
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn pooled(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    fn put(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+
+        if buffers.len() < self.max_pooled {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// A buffer borrowed from a BufferPool, which goes back to the pool when dropped.
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Vec<u8>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn reuses_returned_buffers() {
+        let pool = BufferPool::new(16, 4);
+
+        let first = pool.get();
+        let first_ptr = first.as_ptr();
+
+        assert_eq!(first.len(), 16);
+
+        drop(first);
+        assert_eq!(pool.pooled(), 1);
+
+        assert_eq!(pool.get().as_ptr(), first_ptr);
+    }
+
+    #[test]
+    pub fn keeps_at_most_max_pooled() {
+        let pool = BufferPool::new(16, 2);
+
+        let buffers = (0..3).map(|_| pool.get()).collect::<Vec<_>>();
+        drop(buffers);
+
+        assert_eq!(pool.pooled(), 2);
+
+        let unpooled = BufferPool::new(16, 0);
+        drop(unpooled.get());
+
+        assert_eq!(unpooled.pooled(), 0);
+    }
+}
@@ -0,0 +1,163 @@
+use http::{request::Authority, Error, Result};
+
+use async_std::net::{IpAddr, SocketAddr, TcpStream};
+use futures::{future::BoxFuture, AsyncRead, AsyncWrite};
+use log::debug;
+
+use crate::dns::DnsCache;
+
+/// Opens the upstream side of a tunnel. Swapping this out lets tests tunnel to in-memory streams
+/// and leaves room for other kinds of egress.
+pub trait Connector: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn connect<'a>(&'a self, authority: &'a Authority) -> BoxFuture<'a, Result<Self::Stream>>;
+}
+
+/// Connects directly over TCP, resolving hosts through a DnsCache.
+pub struct TcpConnector {
+    dns: DnsCache,
+    block_internal: bool,
+}
+
+impl TcpConnector {
+    pub fn new(dns: DnsCache) -> Self {
+        Self {
+            dns,
+            block_internal: false,
+        }
+    }
+
+    /// Refuse to connect to loopback, private and link-local addresses, so a broadened allowlist or
+    /// a hostile DNS record can't point the proxy at internal services. Off by default.
+    pub fn block_internal_addresses(self, block: bool) -> Self {
+        Self {
+            block_internal: block,
+            ..self
+        }
+    }
+}
+
+impl Connector for TcpConnector {
+    type Stream = TcpStream;
+
+    fn connect<'a>(&'a self, authority: &'a Authority) -> BoxFuture<'a, Result<TcpStream>> {
+        Box::pin(async move {
+            let addrs = self.dns.resolve(&authority.to_connect_string()?).await.map_err(|e| {
+                debug!("Failed to resolve {}: {:?}", authority, e);
+                Error::DnsLookupFailed
+            })?;
+
+            if addrs.is_empty() {
+                return Err(Error::DnsLookupFailed);
+            }
+
+            let addrs = addrs
+                .into_iter()
+                .filter(|a| !(self.block_internal && is_internal(a.ip())))
+                .collect::<Vec<_>>();
+
+            if addrs.is_empty() {
+                return Err(Error::ForbiddenUpstream);
+            }
+
+            Ok(connect_any(&addrs).await?)
+        })
+    }
+}
+
+/// Whether the address belongs to this host or a private network rather than the internet.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7.
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10.
+                || (first & 0xffc0) == 0xfe80
+                // IPv4-mapped and compatible addresses get checked as the IPv4 address they carry.
+                || ip.to_ipv4().map_or(false, |v4| is_internal(IpAddr::V4(v4)))
+        },
+    }
+}
+
+/// Tries each resolved address in turn, so an upstream with both A and AAAA records is reachable
+/// over whichever family works.
+async fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+
+    for addr in addrs {
+        match TcpStream::connect(*addr).await {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                debug!("Failed to connect to {}: {:?}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No addresses to connect to")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_std::{net::TcpListener, task};
+
+    use std::time::Duration;
+
+    #[test]
+    pub fn can_resolve_and_connect_to_ipv6_upstreams() {
+        task::block_on(async {
+            let upstream = match TcpListener::bind("[::1]:0").await {
+                Ok(l) => l,
+                // Nothing to test on hosts without IPv6.
+                Err(_) => return,
+            };
+
+            let authority = Authority {
+                domain: "::1".to_owned(),
+                port: Some(upstream.local_addr().unwrap().port()),
+            };
+
+            let connector = TcpConnector::new(DnsCache::new(Duration::from_secs(60)));
+            let connection = connector.connect(&authority).await.unwrap();
+            let (accepted, _) = upstream.accept().await.unwrap();
+
+            assert!(connection.peer_addr().unwrap().is_ipv6());
+            assert_eq!(connection.local_addr().unwrap(), accepted.peer_addr().unwrap());
+        });
+    }
+
+    #[test]
+    pub fn classifies_internal_addresses() {
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(is_internal(ip.parse().unwrap()), "{} should be internal", ip);
+        }
+
+        for ip in &["151.101.1.1", "8.8.8.8", "2606:4700::1111"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{} should not be internal", ip);
+        }
+    }
+
+    #[test]
+    pub fn connect_any_falls_back_to_later_addresses() {
+        task::block_on(async {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+            // Bind then drop a listener to get an address nothing is listening on.
+            let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+            let connection = connect_any(&[dead, upstream.local_addr().unwrap()]).await.unwrap();
+
+            assert_eq!(connection.peer_addr().unwrap(), upstream.local_addr().unwrap());
+        });
+    }
+}
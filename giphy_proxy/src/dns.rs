@@ -0,0 +1,120 @@
+use async_std::net::{SocketAddr, ToSocketAddrs};
+use futures::future::BoxFuture;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Looks up the addresses for a host:port string.
+pub type Resolver = Box<dyn Fn(&str) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> + Send + Sync>;
+
+/// Remembers DNS resolutions for a fixed time so we aren't looking up the same upstream on every
+/// CONNECT. Entries are keyed by host:port.
+pub struct DnsCache {
+    ttl: Duration,
+    resolver: Resolver,
+    entries: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl DnsCache {
+    /// A cache backed by the system resolver.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_resolver(ttl, Box::new(|host_port: &str| {
+            let host_port = host_port.to_owned();
+
+            Box::pin(async move {
+                Ok(host_port.to_socket_addrs().await?.collect())
+            })
+        }))
+    }
+
+    pub fn with_resolver(ttl: Duration, resolver: Resolver) -> Self {
+        Self {
+            ttl,
+            resolver,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached addresses for host_port, doing a fresh lookup if we don't have any or
+    /// they've expired. Empty results aren't cached.
+    pub async fn resolve(&self, host_port: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addrs) = self.cached(host_port) {
+            return Ok(addrs);
+        }
+
+        let addrs = (self.resolver)(host_port).await?;
+
+        if !addrs.is_empty() {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(host_port.to_owned(), (Instant::now() + self.ttl, addrs.clone()));
+        }
+
+        Ok(addrs)
+    }
+
+    fn cached(&self, host_port: &str) -> Option<Vec<SocketAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(host_port) {
+            Some((expires, addrs)) if Instant::now() < *expires => Some(addrs.clone()),
+            Some(_) => {
+                entries.remove(host_port);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_std::task;
+    use futures::future;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_cache(ttl: Duration) -> (DnsCache, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+
+        let cache = DnsCache::with_resolver(ttl, Box::new(move |_: &str| {
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            Box::pin(future::ready(Ok(vec!["127.0.0.1:443".parse().unwrap()])))
+        }));
+
+        (cache, lookups)
+    }
+
+    #[test]
+    pub fn expired_entries_are_looked_up_again() {
+        let (cache, lookups) = counting_cache(Duration::from_millis(0));
+
+        task::block_on(async {
+            cache.resolve("api.giphy.com:443").await.unwrap();
+            cache.resolve("api.giphy.com:443").await.unwrap();
+        });
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    pub fn entries_are_keyed_by_port() {
+        let (cache, lookups) = counting_cache(Duration::from_secs(60));
+
+        task::block_on(async {
+            cache.resolve("api.giphy.com:443").await.unwrap();
+            cache.resolve("api.giphy.com:80").await.unwrap();
+            cache.resolve("api.giphy.com:443").await.unwrap();
+        });
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+}
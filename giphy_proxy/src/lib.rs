@@ -1,10 +1,124 @@
-use http::{request::*, response::*, Error, HttpServerBuilder, Result};
+pub mod buffer_pool;
+pub mod connector;
+pub mod dns;
+pub mod sni;
+
+use http::{request::*, response::*, log_access, ConnInfo, CountingStream, Error, HttpServerBuilder, Result};
 
 use async_std::{
-    net::{TcpStream, ToSocketAddrs},
+    net::ToSocketAddrs,
 };
-use log::{debug, error, info};
-use futures::{AsyncReadExt, AsyncWriteExt};
+use log::{debug, error, info, Level};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{
+    buffer_pool::BufferPool,
+    connector::{Connector, TcpConnector},
+    dns::DnsCache,
+    sni::{read_client_hello, Sni, SniSniffer, SniffingReader},
+};
+
+/// How long we trust a DNS resolution for an upstream.
+const DNS_TTL: Duration = Duration::from_secs(60);
+
+/// How long a client connection can go without starting a request before we close it.
+const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client requiring a matching SNI has to send its ClientHello once the tunnel is up.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much each direction of a tunnel copies at a time.
+const COPY_BUFFER_LEN: usize = 1024;
+
+/// How many copy buffers we keep for reuse once their tunnels close.
+const DEFAULT_MAX_POOLED_BUFFERS: usize = 256;
+
+/// The only upstream we tunnel to.
+const ALLOWED_DOMAIN: &str = "api.giphy.com";
+
+/// State shared by every connection the proxy serves.
+pub struct ProxyState<C = TcpConnector> {
+    connector: C,
+    max_total_tunnels: Option<usize>,
+    open_tunnels: AtomicUsize,
+    log_sni: bool,
+    require_matching_sni: bool,
+    buffers: BufferPool,
+}
+
+impl<C: Connector> ProxyState<C> {
+    pub fn new(connector: C) -> Self {
+        Self {
+            connector,
+            max_total_tunnels: None,
+            open_tunnels: AtomicUsize::new(0),
+            log_sni: false,
+            require_matching_sni: false,
+            buffers: BufferPool::new(COPY_BUFFER_LEN, DEFAULT_MAX_POOLED_BUFFERS),
+        }
+    }
+
+    /// Caps how many tunnels can be open at once across all clients. Further CONNECTs get a 503
+    /// until one closes. Unlimited by default.
+    pub fn max_total_tunnels(self, max: usize) -> Self {
+        Self {
+            max_total_tunnels: Some(max),
+            ..self
+        }
+    }
+
+    /// Log the server name from the ClientHello that starts each tunnel, so operators can see
+    /// where clients are going without decrypting anything. The bytes are forwarded untouched.
+    /// Off by default.
+    pub fn log_sni(self, log_sni: bool) -> Self {
+        Self {
+            log_sni,
+            ..self
+        }
+    }
+
+    /// Close tunnels whose TLS ClientHello names a different host than the CONNECT did, or none at
+    /// all, so clients can't front past the allowlist. Nothing reaches upstream until the SNI has
+    /// been checked. Off by default.
+    pub fn require_matching_sni(self, require: bool) -> Self {
+        Self {
+            require_matching_sni: require,
+            ..self
+        }
+    }
+
+    /// How many tunnel copy buffers to keep around for reuse. 0 allocates fresh buffers for every
+    /// tunnel. Defaults to 256.
+    pub fn max_pooled_buffers(self, max: usize) -> Self {
+        Self {
+            buffers: BufferPool::new(COPY_BUFFER_LEN, max),
+            ..self
+        }
+    }
+
+    /// Takes a slot for a tunnel, or None if we're at the cap.
+    fn open_tunnel(&self) -> Option<TunnelPermit<'_>> {
+        let max = self.max_total_tunnels.unwrap_or(usize::MAX);
+
+        self.open_tunnels
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| if open < max { Some(open + 1) } else { None })
+            .ok()
+            .map(|_| TunnelPermit(&self.open_tunnels))
+    }
+}
+
+/// Holds a tunnel's slot under ProxyState's cap until dropped.
+struct TunnelPermit<'a>(&'a AtomicUsize);
+
+impl Drop for TunnelPermit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub async fn server_main() -> Result<()> {
     simple_logger::SimpleLogger::new().init().unwrap();
@@ -19,10 +133,14 @@ pub async fn server_main() -> Result<()> {
         .next()
         .unwrap();
 
+    let connector = TcpConnector::new(DnsCache::new(DNS_TTL)).block_internal_addresses(true);
+    let state = Arc::new(ProxyState::new(connector));
+
     HttpServerBuilder::new()
         .bind_addr(addrs)
+        .first_byte_timeout(Some(FIRST_BYTE_TIMEOUT))
         .build()?
-        .run(handle_proxy)
+        .run(move |request, stream, info| handle_proxy(request, stream, info, state.clone()))
         .await?;
 
     Ok(())
@@ -32,100 +150,262 @@ pub async fn server_main() -> Result<()> {
 /// We parse the request, open a socket to the destination (if valid), then proxy data in both
 /// directions until either stream closes. We then return a ConnectionClosed error, but the client
 /// should have received what it wanted.
-async fn handle_proxy(request: Request, stream: TcpStream) -> Result<Response> {
+async fn handle_proxy<S, C>(mut request: Request, mut stream: S, info: ConnInfo, state: Arc<ProxyState<C>>) -> Result<Response>
+    where S: AsyncRead + AsyncWrite + Unpin,
+          C: Connector
+{
     info!("Got request: {:?}", request);
 
+    let started = Instant::now();
+    let request_line = request.start_line.to_string();
+
+    // Clients may start their TLS handshake without waiting for our response, in which case parsing
+    // the request already read the start of it.
+    let mut leftover = request.take_leftover();
+
+    if request.start_line.method == Method::OPTIONS {
+        return Ok(options_response());
+    }
+
     if request.start_line.method != Method::CONNECT {
         error!("Method is not CONNECT");
-        return Ok(Response::error_response(Status::MethodNotAllowed, ""));
+        return Ok(with_allow_header(Response::empty(Status::MethodNotAllowed)));
+    }
+
+    // Anything after the head is tunnel data, so a body would be indistinguishable from it.
+    if request.expects_body() {
+        error!("CONNECT request declares a body");
+        return Ok(Response::error_response(
+            Status::BadRequest,
+            "CONNECT requests can't have a body",
+        ));
     }
 
+    // CONNECT only makes sense with authority form, so tell the client what they sent instead.
     let host = match request.start_line.target {
         Target::Authority(a) => a,
-        _ => {
-            error!("Invalid proxy target");
+        Target::Path(_) => {
+            error!("CONNECT target is a path");
             return Ok(Response::error_response(
                 Status::BadRequest,
-                "Invalid proxy target",
+                "CONNECT target must be host:port, not a path",
+            ));
+        }
+        Target::Url(_) => {
+            error!("CONNECT target is a URL");
+            return Ok(Response::error_response(
+                Status::BadRequest,
+                "CONNECT target must be host:port, not a URL",
+            ));
+        }
+        Target::Glob => {
+            error!("CONNECT target is *");
+            return Ok(Response::error_response(
+                Status::BadRequest,
+                "CONNECT target must be host:port, not *",
             ));
         }
     };
 
-    if let Some(port) = host.port {
-        if port != 443 {
+    match host.port {
+        Some(443) => {},
+        Some(port) => {
             error!("Invalid port {}", port);
             return Ok(Response::error_response(
                 Status::BadRequest,
                 "Invalid port. Must use 443",
-            ));    
+            ));
+        },
+        None => {
+            error!("CONNECT target has no port");
+            return Ok(Response::error_response(
+                Status::BadRequest,
+                "CONNECT target must specify a port",
+            ));
         }
     }
 
-    if host.domain != "api.giphy.com" || host.port.is_none() {
-        error!("Invalid target domain: {}", host.domain);
-        return Ok(Response::error_response(
-            Status::BadRequest,
-            "Invalid proxy target",
-        ));
-    }
-
-    let addr = format!("{}:{}", host.domain, host.port.unwrap_or(0)).to_socket_addrs().await?
-        .into_iter()
-        .next();
-
-    let addr = match addr {
-        Some(s) => s,
-        None => {
-            error!("DNS lookup failed.");
+    let host = match normalize_host(&host.domain) {
+        Some(domain) if domain == ALLOWED_DOMAIN => Authority { domain, ..host },
+        _ => {
+            error!("Invalid target domain: {}", host.domain);
             return Ok(Response::error_response(
-                Status::BadGateway,
-                "Failed to proxy to remote service",
+                Status::BadRequest,
+                "Invalid proxy target",
             ));
         }
     };
 
-    let proxied_connection = match TcpStream::connect(addr).await {
+    // Held until the tunnel closes, including its upstream connection.
+    let _permit = match state.open_tunnel() {
+        Some(p) => p,
+        None => {
+            error!("Refusing CONNECT, too many open tunnels");
+            return Ok(Response::service_unavailable(None));
+        }
+    };
+
+    let mut proxied_connection = match state.connector.connect(&host).await {
         Ok(s) => s,
+        Err(Error::ForbiddenUpstream) => {
+            error!("Refusing to connect to an internal address for {}", host);
+            return Ok(Response::error_response(
+                Status::Forbidden,
+                "Proxy target resolves to a forbidden address",
+            ));
+        }
         Err(e) => {
             error!("Failed to connect to remote service. {:?}", e);
             return Ok(Response::error_response(
                 Status::BadGateway,
-                "Failed to proxy to remote service",
+                &format!("Failed to proxy to remote service: {}", upstream_failure_summary(&e)),
             ));
         }
     };
 
     info!("Connection established");
 
-    let ok_response = Response::error_response(Status::Ok, "");
-    ok_response.write_to_stream(stream.clone()).await?;
+    Response::connect_established().write_to_stream(&mut stream).await?;
+
+    if state.require_matching_sni {
+        let (hello, sni) = match async_std::future::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(&mut stream, leftover)).await {
+            Ok(read) => read?,
+            Err(_) => {
+                error!("Closing tunnel to {}, no ClientHello within {:?}", host, CLIENT_HELLO_TIMEOUT);
+                return Err(Error::Timeout);
+            }
+        };
+
+        match sni {
+            Sni::Found(name) if normalize_host(&name).as_deref() == Some(host.domain.as_str()) => {},
+            sni => {
+                error!("Closing tunnel to {}, ClientHello SNI was {:?}", host, sni);
+                return Err(Error::ForbiddenUpstream);
+            }
+        }
 
-    let s1 = proxied_connection.clone();
-    let s2 = stream.clone();
+        leftover = hello;
+    }
 
-    let read_proxy = tokio::spawn(async move {
-        let _ = stream_copy(s1, s2).await;
-    });
+    // The ClientHello may have arrived along with the CONNECT.
+    let mut sniffer = if state.log_sni { Some(SniSniffer::new()) } else { None };
 
-    let read_client = tokio::spawn(async move {
-        let _ = stream_copy(stream, proxied_connection).await;
-    });
+    if leftover.len() > 0 {
+        if let Some(sniffer) = &mut sniffer {
+            sniffer.feed(&leftover);
+        }
 
-    let _ = read_client.await;
-    let _ = read_proxy.await;
+        proxied_connection.write_all(&leftover).await?;
+    }
+
+    // Copy on this connection's task rather than spawning, so the server closing the connection
+    // at its drain deadline tears the tunnel down too.
+    let (sent, received) = tunnel(stream, proxied_connection, sniffer, &state.buffers).await;
+
+    // The server only logs responses it writes, so tunnels log their own line once they close.
+    log_access(info.peer_addr(), Some(&request_line), Status::Ok, sent, started.elapsed(), Some(received));
 
     Err(Error::ConnectionClosed)
 }
 
-async fn stream_copy(mut s1: TcpStream, mut s2: TcpStream) -> Result<()> {
-    let mut buf: Vec<u8> = vec![0; 1024];
+/// Puts a domain in the form we compare against the allowlist: percent-decoded, lowercase and
+/// without the trailing dot of a fully qualified name. Returns None for malformed escapes.
+fn normalize_host(domain: &str) -> Option<String> {
+    let bytes = domain.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    let mut domain = String::from_utf8(decoded).ok()?.to_ascii_lowercase();
+
+    if domain.ends_with('.') {
+        domain.pop();
+    }
+
+    Some(domain)
+}
+
+/// Why connecting upstream failed, in terms safe to hand the client. This deliberately never
+/// includes addresses or the underlying error message.
+fn upstream_failure_summary(err: &Error) -> &'static str {
+    match (err, err.io_kind()) {
+        (Error::DnsLookupFailed, _) => "dns lookup failed",
+        (_, Some(std::io::ErrorKind::ConnectionRefused)) => "connection refused",
+        (_, Some(std::io::ErrorKind::TimedOut)) => "timed out",
+        (_, Some(std::io::ErrorKind::ConnectionReset)) => "connection reset",
+        _ => "connection failed",
+    }
+}
+
+/// Tunnels routinely end with one side resetting or hanging up on us, so those errors only warrant
+/// a debug log. Anything else is unexpected.
+fn io_error_log_level(kind: std::io::ErrorKind) -> Level {
+    match kind {
+        std::io::ErrorKind::ConnectionReset
+        | std::io::ErrorKind::ConnectionAborted
+        | std::io::ErrorKind::BrokenPipe => Level::Debug,
+        _ => Level::Error,
+    }
+}
+
+/// The 200 response to OPTIONS, for * or any other target, advertising the only method we support.
+fn options_response() -> Response {
+    with_allow_header(Response::ok())
+}
+
+/// Adds the Allow header listing the methods we support, which OPTIONS and 405 responses carry.
+fn with_allow_header(mut response: Response) -> Response {
+    response.set_header("Allow", "CONNECT");
+
+    response
+}
+
+/// Copies between the client and upstream in both directions until both sides are done, returning
+/// how many bytes went to the client and how many came from it. Each stream is split into halves
+/// rather than cloned, so this works for streams that can't be cloned, like TLS. Given a sniffer,
+/// it sees what the client sends. Copy buffers come from, and go back to, buffers.
+pub async fn tunnel<A, B>(client: A, upstream: B, sniffer: Option<SniSniffer>, buffers: &BufferPool) -> (usize, usize)
+    where A: AsyncRead + AsyncWrite + Unpin,
+          B: AsyncRead + AsyncWrite
+{
+    let client = CountingStream::new(client);
+    let counts = client.counts();
+
+    let (client_read, client_write) = client.split();
+    let (upstream_read, upstream_write) = upstream.split();
+
+    futures::future::join(
+        stream_copy(upstream_read, client_write, buffers),
+        stream_copy(SniffingReader::new(client_read, sniffer), upstream_write, buffers),
+    ).await;
+
+    (counts.written(), counts.read())
+}
+
+/// Copies s1 into s2 until s1 ends or either side fails.
+async fn stream_copy<R, W>(mut s1: R, mut s2: W, buffers: &BufferPool)
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin
+{
+    let mut buf = buffers.get();
 
     debug!("Connecting streams...");
 
     loop {
         match s1.read(&mut buf).await {
             Ok(bytes_read) => {
-                info!("Got {} bytes from {:?}", bytes_read, s1.local_addr());
+                info!("Got {} bytes", bytes_read);
                 if bytes_read == 0 {
                     info!("Connection closed.");
                     break;
@@ -133,19 +413,601 @@ async fn stream_copy(mut s1: TcpStream, mut s2: TcpStream) -> Result<()> {
 
                 let (data, _) = buf.split_at(bytes_read);
 
-                match s2.write_all(data).await {
-                    Ok(_) => {},
+                match http::write_fully(&mut s2, data).await {
+                    Ok(()) => {},
+                    Err(Error::PeerClosed) => {
+                        debug!("Peer stopped accepting data");
+                        break;
+                    },
                     Err(e) => {
-                        error!("Write failed: {:?}", e);
+                        http::log_rate_limited(
+                            "tunnel-write",
+                            e.io_kind().map_or(Level::Error, io_error_log_level),
+                            format_args!("Write failed: {:?}", e)
+                        );
+                        break;
                     }
-                };
+                }
             },
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
             Err(e) => {
-                error!("{:?}", e);
+                http::log_rate_limited("tunnel-read", io_error_log_level(e.kind()), format_args!("Read failed: {:?}", e));
                 break;
             }
         }
     }
+}
 
-    Err(Error::ConnectionClosed)
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::dns::Resolver;
+
+    use async_std::{
+        io::Cursor,
+        net::{SocketAddr, TcpListener, TcpStream},
+        task,
+    };
+    use futures::future::{self, BoxFuture};
+    use http::test_support::TestTransport;
+
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Hands out a single in-memory stream and remembers where it was asked to connect.
+    struct MockConnector {
+        stream: Mutex<Option<TestTransport>>,
+        connected_to: Mutex<Option<Authority>>,
+    }
+
+    impl Connector for MockConnector {
+        type Stream = TestTransport;
+
+        fn connect<'a>(&'a self, authority: &'a Authority) -> BoxFuture<'a, Result<TestTransport>> {
+            *self.connected_to.lock().unwrap() = Some(authority.clone());
+
+            let stream = self.stream.lock().unwrap().take();
+
+            Box::pin(future::ready(stream.ok_or(Error::ConnectionClosed)))
+        }
+    }
+
+    fn test_state() -> Arc<ProxyState> {
+        Arc::new(ProxyState::new(TcpConnector::new(DnsCache::new(DNS_TTL))))
+    }
+
+    /// A connected pair of sockets standing in for the client and our end of its connection.
+    async fn loopback_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (client, server)
+    }
+
+    /// Parses the request, runs it through handle_proxy on a loopback connection and returns the
+    /// serialized response.
+    fn proxy_response(request: &str) -> String {
+        let request = task::block_on(Request::parse(Cursor::new(request.as_bytes()), &ParseOptions::default())).unwrap();
+
+        proxy_response_to(request)
+    }
+
+    fn proxy_response_to(request: Request) -> String {
+        proxy_response_via(request, test_state())
+    }
+
+    fn proxy_response_via<C: Connector>(request: Request, state: Arc<ProxyState<C>>) -> String {
+        task::block_on(async {
+            let (client, server) = loopback_pair().await;
+
+            let response = handle_proxy(request, server, ConnInfo::new(client.local_addr().ok()), state).await.unwrap();
+
+            let mut written = Cursor::new(vec![]);
+            response.write_to_stream(&mut written).await.unwrap();
+
+            String::from_utf8(written.into_inner()).unwrap()
+        })
+    }
+
+    #[test]
+    pub fn options_glob_advertises_connect() {
+        let response = proxy_response("OPTIONS * HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Allow:CONNECT\r\n"));
+    }
+
+    #[test]
+    pub fn options_for_a_path_advertises_connect() {
+        let response = proxy_response("OPTIONS /v1/gifs HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Allow:CONNECT\r\n"));
+        assert!(response.contains("Content-length:0\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    pub fn rejects_other_methods_with_405() {
+        let response = proxy_response("GET /v1/gifs HTTP/1.1\r\nHost: api.giphy.com\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("Allow:CONNECT\r\n"));
+    }
+
+    #[test]
+    pub fn disconnects_are_not_logged_as_errors() {
+        assert_eq!(io_error_log_level(std::io::ErrorKind::ConnectionReset), Level::Debug);
+        assert_eq!(io_error_log_level(std::io::ErrorKind::ConnectionAborted), Level::Debug);
+        assert_eq!(io_error_log_level(std::io::ErrorKind::BrokenPipe), Level::Debug);
+        assert_eq!(io_error_log_level(std::io::ErrorKind::PermissionDenied), Level::Error);
+    }
+
+    #[test]
+    pub fn connect_rejects_non_authority_targets() {
+        // Request::parse already rejects these, so build them directly.
+        let connect_to = |target: &str| {
+            RequestBuilder::new().method(Method::CONNECT).target(target).build().unwrap()
+        };
+
+        let response = proxy_response_to(connect_to("/giphy"));
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("CONNECT target must be host:port, not a path"));
+
+        let response = proxy_response_to(connect_to("https://api.giphy.com/"));
+
+        assert!(response.ends_with("CONNECT target must be host:port, not a URL"));
+
+        let response = proxy_response_to(connect_to("*"));
+
+        assert!(response.ends_with("CONNECT target must be host:port, not *"));
+    }
+
+    #[test]
+    pub fn connect_reuses_cached_resolution() {
+        task::block_on(async {
+            // An upstream that hangs up straight away, so each tunnel ends as soon as it opens.
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+
+            task::spawn(async move {
+                while upstream.accept().await.is_ok() {}
+            });
+
+            let lookups = Arc::new(AtomicUsize::new(0));
+            let counter = lookups.clone();
+
+            let resolver: Resolver = Box::new(move |_: &str| {
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                Box::pin(future::ready(Ok(vec![upstream_addr])))
+            });
+
+            let connector = TcpConnector::new(DnsCache::with_resolver(Duration::from_secs(60), resolver));
+            let state = Arc::new(ProxyState::new(connector));
+
+            for _ in 0..2 {
+                let request = Request::parse(
+                    Cursor::new(&b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n"[..]),
+                    &ParseOptions::default()
+                ).await.unwrap();
+
+                let (client, server) = loopback_pair().await;
+                client.shutdown(std::net::Shutdown::Write).unwrap();
+
+                let result = handle_proxy(request, server, ConnInfo::new(client.local_addr().ok()), state.clone()).await;
+
+                assert!(matches!(result, Err(Error::ConnectionClosed)));
+            }
+
+            assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    pub fn tunnels_between_client_and_connector_stream() {
+        http::test_support::capture_logs();
+
+        let (mut client, server) = TestTransport::pair();
+        let (upstream, mut remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream.clone())),
+            connected_to: Mutex::new(None),
+        }));
+
+        task::block_on(async {
+            // Both ends say their piece and hang up, so the tunnel ends once it's copied everything.
+            client.write_all(b"hello").await.unwrap();
+            client.close().await.unwrap();
+            remote.write_all(b"world").await.unwrap();
+            remote.close().await.unwrap();
+
+            let request = RequestBuilder::new()
+                .method(Method::CONNECT)
+                .target("api.giphy.com:443")
+                .build()
+                .unwrap();
+
+            let result = handle_proxy(request, server.clone(), ConnInfo::new(None), state.clone()).await;
+
+            assert!(matches!(result, Err(Error::ConnectionClosed)));
+
+            // Close our ends the way the server would so the reads below see EOF.
+            server.clone().close().await.unwrap();
+            upstream.clone().close().await.unwrap();
+
+            let mut to_client = String::new();
+            client.read_to_string(&mut to_client).await.unwrap();
+
+            let mut to_remote = String::new();
+            remote.read_to_string(&mut to_remote).await.unwrap();
+
+            // The tunnel's 200 has no Content-Length, so the upstream's bytes follow straight on.
+            assert_eq!(to_client, "HTTP/1.1 200 OK\r\n\r\nworld");
+            assert_eq!(to_remote, "hello");
+        });
+
+        let logs = http::test_support::captured_logs();
+
+        assert!(logs.iter().any(|l| {
+            l.starts_with("access: - \"CONNECT api.giphy.com:443 HTTP/1.1\" 200 5 ") && l.ends_with("ms received=5")
+        }));
+
+        assert_eq!(
+            *state.connector.connected_to.lock().unwrap(),
+            Some(Authority { domain: "api.giphy.com".to_owned(), port: Some(443) })
+        );
+    }
+
+    #[test]
+    pub fn connect_to_internal_addresses_is_forbidden_when_guarded() {
+        for internal in &["127.0.0.1:443", "169.254.169.254:443"] {
+            let addr = internal.parse::<SocketAddr>().unwrap();
+
+            // Stands in for a DNS record pointing the allowed domain somewhere it shouldn't.
+            let resolver: Resolver = Box::new(move |_: &str| Box::pin(future::ready(Ok(vec![addr]))));
+            let connector = TcpConnector::new(DnsCache::with_resolver(DNS_TTL, resolver))
+                .block_internal_addresses(true);
+
+            let request = RequestBuilder::new()
+                .method(Method::CONNECT)
+                .target("api.giphy.com:443")
+                .build()
+                .unwrap();
+
+            let response = proxy_response_via(request, Arc::new(ProxyState::new(connector)));
+
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{} wasn't blocked", internal);
+        }
+    }
+
+    #[test]
+    pub fn normalizes_hosts_before_comparing() {
+        assert_eq!(normalize_host("api.giphy.com.").as_deref(), Some("api.giphy.com"));
+        assert_eq!(normalize_host("API.Giphy.com").as_deref(), Some("api.giphy.com"));
+        assert_eq!(normalize_host("api%2Egiphy.com").as_deref(), Some("api.giphy.com"));
+        assert_eq!(normalize_host("api.giphy.com%"), None);
+        assert_eq!(normalize_host("api.giphy.com%zz"), None);
+    }
+
+    #[test]
+    pub fn fully_qualified_domains_are_allowed() {
+        let (client, server) = TestTransport::pair();
+        let (upstream, remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream)),
+            connected_to: Mutex::new(None),
+        }));
+
+        task::block_on(async {
+            // Nobody has anything to say, so the tunnel closes as soon as it opens.
+            client.clone().close().await.unwrap();
+            remote.clone().close().await.unwrap();
+
+            let request = Request::parse(
+                Cursor::new(&b"CONNECT api.giphy.com.:443 HTTP/1.1\r\n\r\n"[..]),
+                &ParseOptions::default()
+            ).await.unwrap();
+
+            let result = handle_proxy(request, server, ConnInfo::new(None), state.clone()).await;
+
+            assert!(matches!(result, Err(Error::ConnectionClosed)));
+        });
+
+        assert_eq!(
+            *state.connector.connected_to.lock().unwrap(),
+            Some(Authority { domain: "api.giphy.com".to_owned(), port: Some(443) })
+        );
+    }
+
+    #[test]
+    pub fn lookalike_domains_are_rejected() {
+        let response = proxy_response("CONNECT api.giphy.com.evil.com:443 HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("Invalid proxy target"));
+    }
+
+    fn connect_with_resolver(resolver: Resolver) -> String {
+        let request = RequestBuilder::new()
+            .method(Method::CONNECT)
+            .target("api.giphy.com:443")
+            .build()
+            .unwrap();
+
+        let connector = TcpConnector::new(DnsCache::with_resolver(DNS_TTL, resolver));
+
+        proxy_response_via(request, Arc::new(ProxyState::new(connector)))
+    }
+
+    #[test]
+    pub fn refused_upstream_connections_say_so() {
+        // Bind then drop a listener to get an address nothing is listening on.
+        let dead = task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap().local_addr().unwrap();
+
+        let response = connect_with_resolver(Box::new(move |_: &str| Box::pin(future::ready(Ok(vec![dead])))));
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.ends_with("Failed to proxy to remote service: connection refused"));
+        assert!(!response.contains("127.0.0.1"));
+    }
+
+    #[test]
+    pub fn failed_lookups_say_so() {
+        let response = connect_with_resolver(Box::new(|_: &str| {
+            Box::pin(future::ready(Err(std::io::Error::new(std::io::ErrorKind::Other, "no such host"))))
+        }));
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.ends_with("Failed to proxy to remote service: dns lookup failed"));
+    }
+
+    #[test]
+    pub fn forwards_bytes_pipelined_after_connect_first() {
+        let (mut client, server) = TestTransport::pair();
+        let (upstream, mut remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream.clone())),
+            connected_to: Mutex::new(None),
+        }));
+
+        let to_remote = task::block_on(async {
+            // The start of a TLS ClientHello, sent without waiting for our 200.
+            client.write_all(b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n\x16\x03\x01").await.unwrap();
+
+            let request = Request::parse(server.clone(), &ParseOptions::default()).await.unwrap();
+
+            client.write_all(b"rest").await.unwrap();
+            client.close().await.unwrap();
+            remote.close().await.unwrap();
+
+            let result = handle_proxy(request, server.clone(), ConnInfo::new(None), state).await;
+
+            assert!(matches!(result, Err(Error::ConnectionClosed)));
+
+            upstream.clone().close().await.unwrap();
+
+            let mut to_remote = vec![];
+            remote.read_to_end(&mut to_remote).await.unwrap();
+
+            to_remote
+        });
+
+        assert_eq!(to_remote, b"\x16\x03\x01rest");
+    }
+
+    #[test]
+    pub fn tunnels_with_a_mismatched_sni_are_closed() {
+        let (mut client, server) = TestTransport::pair();
+        let (upstream, mut remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream.clone())),
+            connected_to: Mutex::new(None),
+        }).require_matching_sni(true));
+
+        let connect_with_sni = |sni: &str| {
+            let mut data = b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n".to_vec();
+            data.extend(crate::sni::test::client_hello(sni));
+            data
+        };
+
+        let to_remote = task::block_on(async {
+            client.write_all(&connect_with_sni("fronted.example.com")).await.unwrap();
+            client.close().await.unwrap();
+
+            let request = Request::parse(server.clone(), &ParseOptions::default()).await.unwrap();
+            let result = handle_proxy(request, server.clone(), ConnInfo::new(None), state).await;
+
+            assert!(matches!(result, Err(Error::ForbiddenUpstream)));
+
+            upstream.clone().close().await.unwrap();
+
+            let mut to_remote = vec![];
+            remote.read_to_end(&mut to_remote).await.unwrap();
+
+            to_remote
+        });
+
+        assert!(to_remote.is_empty());
+    }
+
+    #[test]
+    pub fn tunnels_with_a_matching_sni_are_forwarded() {
+        let (mut client, server) = TestTransport::pair();
+        let (upstream, mut remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream.clone())),
+            connected_to: Mutex::new(None),
+        }).require_matching_sni(true));
+
+        let hello = crate::sni::test::client_hello("API.giphy.com");
+
+        let to_remote = task::block_on(async {
+            client.write_all(b"CONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n").await.unwrap();
+
+            let request = Request::parse(server.clone(), &ParseOptions::default()).await.unwrap();
+
+            client.write_all(&hello).await.unwrap();
+            client.close().await.unwrap();
+            remote.close().await.unwrap();
+
+            let result = handle_proxy(request, server.clone(), ConnInfo::new(None), state).await;
+
+            assert!(matches!(result, Err(Error::ConnectionClosed)));
+
+            upstream.clone().close().await.unwrap();
+
+            let mut to_remote = vec![];
+            remote.read_to_end(&mut to_remote).await.unwrap();
+
+            to_remote
+        });
+
+        assert_eq!(to_remote, hello);
+    }
+
+    #[test]
+    pub fn tunnel_copies_both_ways_over_split_streams() {
+        let (mut client, proxy_side) = TestTransport::pair();
+        let (upstream_side, mut remote) = TestTransport::pair();
+
+        task::block_on(async {
+            client.write_all(b"hello").await.unwrap();
+            client.close().await.unwrap();
+            remote.write_all(b"world!").await.unwrap();
+            remote.close().await.unwrap();
+
+            let (sent, received) = tunnel(proxy_side.clone(), upstream_side.clone(), None, &BufferPool::new(1024, 1)).await;
+
+            assert_eq!((sent, received), (6, 5));
+
+            proxy_side.clone().close().await.unwrap();
+            upstream_side.clone().close().await.unwrap();
+
+            let mut to_client = String::new();
+            client.read_to_string(&mut to_client).await.unwrap();
+
+            let mut to_remote = String::new();
+            remote.read_to_string(&mut to_remote).await.unwrap();
+
+            assert_eq!(to_client, "world!");
+            assert_eq!(to_remote, "hello");
+        });
+    }
+
+    #[test]
+    pub fn upstream_resets_are_not_logged_as_errors() {
+        http::test_support::capture_logs();
+
+        let (mut client, proxy_side) = TestTransport::pair();
+        let (upstream_side, remote) = TestTransport::pair();
+
+        task::block_on(async {
+            client.close().await.unwrap();
+            remote.reset();
+
+            tunnel(proxy_side, upstream_side, None, &BufferPool::new(1024, 1)).await;
+        });
+
+        let errors = http::test_support::captured_logs_at(Level::Error);
+
+        assert!(!errors.iter().any(|l| l.contains("ConnectionReset")), "{:?}", errors);
+    }
+
+    #[test]
+    pub fn tunnel_logs_the_sni_and_forwards_the_client_hello() {
+        http::test_support::capture_logs();
+
+        let (mut client, proxy_side) = TestTransport::pair();
+        let (upstream_side, mut remote) = TestTransport::pair();
+        let hello = crate::sni::test::client_hello("sni-test.giphy.com");
+
+        task::block_on(async {
+            // Arrives in two pieces, so the sniffer has to wait for the rest of the record.
+            client.write_all(&hello[..10]).await.unwrap();
+            client.write_all(&hello[10..]).await.unwrap();
+            client.close().await.unwrap();
+            remote.close().await.unwrap();
+
+            tunnel(proxy_side.clone(), upstream_side.clone(), Some(SniSniffer::new()), &BufferPool::new(1024, 1)).await;
+
+            upstream_side.clone().close().await.unwrap();
+
+            let mut to_remote = vec![];
+            remote.read_to_end(&mut to_remote).await.unwrap();
+
+            assert_eq!(to_remote, hello);
+        });
+
+        let logs = http::test_support::captured_logs();
+
+        assert!(logs.iter().any(|l| l.ends_with("Client is tunneling to SNI sni-test.giphy.com")));
+    }
+
+    #[test]
+    pub fn tunnels_beyond_the_cap_get_503() {
+        let (_client, server) = TestTransport::pair();
+        let (upstream, _remote) = TestTransport::pair();
+
+        let state = Arc::new(ProxyState::new(MockConnector {
+            stream: Mutex::new(Some(upstream)),
+            connected_to: Mutex::new(None),
+        }).max_total_tunnels(1));
+
+        let connect = || RequestBuilder::new()
+            .method(Method::CONNECT)
+            .target("api.giphy.com:443")
+            .build()
+            .unwrap();
+
+        task::block_on(async {
+            // Nobody hangs up, so this tunnel stays open for as long as it's polled.
+            let first = handle_proxy(connect(), server, ConnInfo::new(None), state.clone());
+
+            let second = async {
+                while state.open_tunnels.load(Ordering::SeqCst) == 0 {
+                    task::yield_now().await;
+                }
+
+                let response = handle_proxy(connect(), TestTransport::pair().1, ConnInfo::new(None), state.clone())
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), Status::ServiceUnavailable);
+            };
+
+            future::select(Box::pin(first), Box::pin(second)).await;
+        });
+
+        // Dropping the first tunnel gave its slot back.
+        assert_eq!(state.open_tunnels.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    pub fn connect_without_a_port_is_rejected() {
+        let response = proxy_response("CONNECT api.giphy.com HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("CONNECT target must specify a port"));
+    }
+
+    #[test]
+    pub fn connect_with_a_body_is_rejected() {
+        let response = proxy_response("CONNECT api.giphy.com:443 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("CONNECT requests can't have a body"));
+
+        let response = proxy_response("CONNECT api.giphy.com:443 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+
+        assert!(response.ends_with("CONNECT requests can't have a body"));
+    }
+}
@@ -1,8 +1,6 @@
 use http::Result;
 
-mod lib;
-
-use crate::lib::server_main;
+use giphy_proxy::server_main;
 
 #[tokio::main]
 async fn main() -> Result<()> {
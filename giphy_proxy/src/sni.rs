@@ -0,0 +1,275 @@
+use futures::{io::AsyncRead, ready, AsyncReadExt};
+use log::{debug, info};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The TLS record header: content type, version and length.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// The largest record TLS allows, which bounds how much we'll hold on to while looking for the SNI.
+const MAX_RECORD_LEN: usize = 16 * 1024 + 2048;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// What we learned from the start of a client's TLS stream.
+#[derive(Debug, PartialEq)]
+pub enum Sni {
+    /// The ClientHello named this host.
+    Found(String),
+
+    /// The first record isn't a ClientHello, doesn't fit in one record or has no SNI.
+    Absent,
+
+    /// We need more of the first record to tell.
+    Incomplete,
+}
+
+/// Looks for the server name in the ClientHello at the start of data. Only the first record is
+/// examined, so a ClientHello split across records counts as having no SNI.
+pub fn parse_sni(data: &[u8]) -> Sni {
+    if data.is_empty() {
+        return Sni::Incomplete;
+    }
+
+    if data[0] != CONTENT_TYPE_HANDSHAKE {
+        return Sni::Absent;
+    }
+
+    if data.len() < RECORD_HEADER_LEN {
+        return Sni::Incomplete;
+    }
+
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+
+    if record_len > MAX_RECORD_LEN {
+        return Sni::Absent;
+    }
+
+    match data.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) {
+        Some(record) => match server_name(&mut Fields(record)) {
+            Some(name) => Sni::Found(name),
+            None => Sni::Absent,
+        },
+        None => Sni::Incomplete,
+    }
+}
+
+/// Reads big endian fields off the front of a slice. Every read is None once the data runs out.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A field prefixed with its length in len_bytes bytes.
+    fn prefixed(&mut self, len_bytes: usize) -> Option<Fields<'a>> {
+        let len = self.take(len_bytes)?.iter().fold(0, |len, b| (len << 8) | *b as usize);
+
+        Some(Fields(self.take(len)?))
+    }
+}
+
+fn server_name(record: &mut Fields) -> Option<String> {
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    let mut hello = record.prefixed(3)?;
+
+    // Version and random.
+    hello.take(2 + 32)?;
+    // Session id, cipher suites and compression methods.
+    hello.prefixed(1)?;
+    hello.prefixed(2)?;
+    hello.prefixed(1)?;
+
+    let mut extensions = hello.prefixed(2)?;
+
+    while let Some(extension_type) = extensions.u16() {
+        let mut extension = extensions.prefixed(2)?;
+
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = extension.prefixed(2)?;
+
+        while let Some(name_type) = names.u8() {
+            let name = names.prefixed(2)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return String::from_utf8(name.0.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads from the client until the ClientHello at the start of its stream can be parsed, starting
+/// with whatever had already been read. Returns everything read, which still needs forwarding, and
+/// what we found. Reads at most one record, and a stream that ends early has no SNI.
+pub async fn read_client_hello<R>(reader: &mut R, mut data: Vec<u8>) -> io::Result<(Vec<u8>, Sni)>
+    where R: AsyncRead + Unpin
+{
+    let mut buf = [0; 1024];
+
+    loop {
+        match parse_sni(&data) {
+            Sni::Incomplete => {},
+            sni => return Ok((data, sni)),
+        }
+
+        match reader.read(&mut buf).await {
+            Ok(0) => return Ok((data, Sni::Absent)),
+            Ok(read) => data.extend_from_slice(&buf[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Watches the bytes a client sends at the start of a tunnel and logs the SNI from its ClientHello.
+/// Holds on to at most one record's worth of bytes, and stops looking as soon as it can tell.
+#[derive(Default)]
+pub struct SniSniffer {
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl SniSniffer {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![],
+            done: false,
+        }
+    }
+
+    /// Looks at the next bytes the client sent. The caller still forwards them itself.
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.done || data.is_empty() {
+            return;
+        }
+
+        self.buffer.extend_from_slice(data);
+
+        match parse_sni(&self.buffer) {
+            Sni::Found(name) => info!("Client is tunneling to SNI {}", name),
+            Sni::Absent => debug!("Tunnel didn't start with a ClientHello carrying an SNI"),
+            Sni::Incomplete => return,
+        }
+
+        self.done = true;
+        self.buffer = vec![];
+    }
+}
+
+/// Feeds everything read from the wrapped reader to a SniSniffer, if given one, without changing
+/// what's read.
+pub struct SniffingReader<R> {
+    inner: R,
+    sniffer: Option<SniSniffer>,
+}
+
+impl<R> SniffingReader<R> {
+    pub fn new(inner: R, sniffer: Option<SniSniffer>) -> Self {
+        Self { inner, sniffer }
+    }
+}
+
+impl<R> AsyncRead for SniffingReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let bytes_read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if let Some(sniffer) = &mut self.sniffer {
+            sniffer.feed(&buf[..bytes_read]);
+        }
+
+        Poll::Ready(Ok(bytes_read))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// A minimal ClientHello record naming host, as a browser would send to start a tunnel.
+    pub fn client_hello(host: &str) -> Vec<u8> {
+        let with_len16 = |data: &[u8]| {
+            let mut out = (data.len() as u16).to_be_bytes().to_vec();
+            out.extend_from_slice(data);
+            out
+        };
+
+        let mut name_entry = vec![NAME_TYPE_HOST_NAME];
+        name_entry.extend(with_len16(host.as_bytes()));
+
+        let mut extensions: Vec<u8> = vec![];
+        // An extension before the SNI, to check we skip it.
+        extensions.extend(&[0x00, 0x17, 0x00, 0x00]);
+        extensions.extend(&EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend(with_len16(&with_len16(&name_entry)));
+
+        let mut hello: Vec<u8> = vec![0x03, 0x03];
+        hello.extend(&[0x42; 32]);
+        hello.extend(&[0x00]);
+        hello.extend(with_len16(&[0x13, 0x01]));
+        hello.extend(&[0x01, 0x00]);
+        hello.extend(with_len16(&extensions));
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend(with_len16(&handshake));
+
+        record
+    }
+
+    #[test]
+    pub fn finds_the_sni_in_a_client_hello() {
+        assert_eq!(parse_sni(&client_hello("api.giphy.com")), Sni::Found("api.giphy.com".to_owned()));
+    }
+
+    #[test]
+    pub fn needs_the_whole_first_record() {
+        let hello = client_hello("api.giphy.com");
+
+        assert_eq!(parse_sni(&hello[..3]), Sni::Incomplete);
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), Sni::Incomplete);
+    }
+
+    #[test]
+    pub fn gives_up_on_anything_else() {
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), Sni::Absent);
+        assert_eq!(parse_sni(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0xff, 0xff]), Sni::Absent);
+        assert_eq!(parse_sni(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0x00, 0x01, 0x02]), Sni::Absent);
+    }
+}
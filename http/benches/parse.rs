@@ -0,0 +1,74 @@
+use async_std::io::Cursor;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::executor::LocalPool;
+use http::request::{ParseOptions, Request, Target};
+use regex::Regex;
+
+/// A typical CONNECT request with a handful of headers.
+fn small_request() -> String {
+    let mut request = "CONNECT api.giphy.com:443 HTTP/1.1\r\n".to_owned();
+
+    for i in 0..10 {
+        request += &format!("X-Header-{}: value {}\r\n", i, i);
+    }
+
+    request + "\r\n"
+}
+
+/// A request whose headers come close to the default per-header and headers-section limits.
+fn near_limit_request() -> String {
+    let options = ParseOptions::default();
+    let mut request = "GET / HTTP/1.1\r\n".to_owned();
+
+    let header_count = options.max_headers_section_len() / options.max_header_len() - 1;
+    let value = "a".repeat(options.max_header_len() - "X-Header-00: ".len() - 1);
+
+    for i in 0..header_count {
+        request += &format!("X-Header-{:02}: {}\r\n", i, value);
+    }
+
+    request + "\r\n"
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let options = ParseOptions::default();
+    let mut group = c.benchmark_group("Request::parse");
+
+    for (name, request) in &[("small", small_request()), ("near_limit", near_limit_request())] {
+        group.throughput(Throughput::Bytes(request.len() as u64));
+
+        group.bench_function(*name, |b| {
+            let mut pool = LocalPool::new();
+
+            b.iter(|| {
+                pool.run_until(async {
+                    Request::parse(Cursor::new(request.as_bytes()), &options).await.unwrap()
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Compares Target::parse's cached authority regex against compiling it on every call, as it used
+/// to.
+fn bench_target_regex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Target::parse");
+    let target = "api.giphy.com:443";
+
+    group.bench_function("per_call_regex", |b| {
+        b.iter(|| {
+            Regex::new(r"^((\d|[[:alpha:]])+\.)+(\d|[[:alpha:]])+(:\d+)?$").unwrap().is_match(target)
+        })
+    });
+
+    group.bench_function("cached_regex", |b| {
+        b.iter(|| Target::parse(target).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_target_regex);
+criterion_main!(benches);
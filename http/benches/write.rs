@@ -0,0 +1,41 @@
+use async_std::io::Cursor;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::executor::LocalPool;
+use http::{
+    response::{Response, Status},
+    Headers, HttpVersion,
+};
+
+use std::iter::FromIterator;
+
+/// About the size of a large gif.
+const BODY_LEN: usize = 4 * 1024 * 1024;
+
+fn large_response(body: &[u8], chunk_size: usize) -> Response {
+    let headers = Headers::from_iter(vec![("Content-Length".to_owned(), format!("{}", body.len()))]);
+
+    Response::new(Status::Ok, HttpVersion::Http1_1, headers, Box::new(Cursor::new(body.to_owned())))
+        .with_body_chunk_size(chunk_size)
+}
+
+fn bench_write_body(c: &mut Criterion) {
+    let body = vec![b'g'; BODY_LEN];
+    let mut group = c.benchmark_group("Response::write_to_stream");
+
+    group.throughput(Throughput::Bytes(BODY_LEN as u64));
+
+    for (name, chunk_size) in &[("128B_chunks", 128), ("16KB_chunks", 16 * 1024)] {
+        group.bench_function(*name, |b| {
+            let mut pool = LocalPool::new();
+
+            b.iter(|| {
+                pool.run_until(large_response(&body, *chunk_size).write_to_stream(futures::io::sink())).unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_write_body);
+criterion_main!(benches);
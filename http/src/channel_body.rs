@@ -0,0 +1,86 @@
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    io::AsyncRead,
+    ready,
+    stream::Stream,
+};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A response body fed over time through a channel. A producer sends chunks as they become
+/// available and the body ends once every sender is dropped, so a response can start going out
+/// before all of its data exists.
+pub struct ChannelBody {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelBody {
+    /// Creates a body along with the sender that feeds it. The channel holds up to buffer chunks
+    /// (plus one per sender) before the producer has to wait for the body to be read.
+    pub fn channel(buffer: usize) -> (Sender<Vec<u8>>, ChannelBody) {
+        let (sender, receiver) = mpsc::channel(buffer);
+
+        let body = Self {
+            receiver,
+            chunk: vec![],
+            pos: 0,
+        };
+
+        (sender, body)
+    }
+}
+
+impl AsyncRead for ChannelBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.pos == self.chunk.len() {
+            match ready!(Pin::new(&mut self.receiver).poll_next(cx)) {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        let pos = self.pos;
+
+        buf[..len].copy_from_slice(&self.chunk[pos..pos + len]);
+        self.pos += len;
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{executor::LocalPool, future, AsyncReadExt, SinkExt};
+
+    #[test]
+    pub fn reads_chunks_in_order() {
+        let (mut sender, mut body) = ChannelBody::channel(1);
+
+        let data = LocalPool::default().run_until(async {
+            let produce = async move {
+                for chunk in &["horse", "billy", "!"] {
+                    sender.send(chunk.as_bytes().to_vec()).await.unwrap();
+                }
+            };
+
+            let mut data = String::new();
+
+            let (_, read) = future::join(produce, body.read_to_string(&mut data)).await;
+            read.unwrap();
+
+            data
+        });
+
+        assert_eq!(data, "horsebilly!");
+    }
+}
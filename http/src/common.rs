@@ -1,4 +1,7 @@
+use futures::{AsyncWrite, AsyncWriteExt};
+
 use std::collections::HashMap;
+use std::io::ErrorKind;
 
 use crate::error::{Error, Result};
 
@@ -32,7 +35,20 @@ impl std::fmt::Display for HttpVersion {
     }
 }
 
-#[derive(Debug)]
+/// Headers that describe a single connection rather than the message, so proxies must not forward
+/// them.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+#[derive(Debug, PartialEq)]
 pub struct Headers {
     pub headers: HashMap<String, String>,
 }
@@ -42,20 +58,390 @@ impl Headers {
         Self { headers: h }
     }
 
+    /// Splits a header line into its name and value at the first colon, so values containing colons
+    /// (ports, times, URLs) survive. Only surrounding whitespace is trimmed. Headers with an empty
+    /// name or value are rejected.
     pub fn parse_header(data: &str) -> Result<(&str, &str)> {
-        let mut splits = data.split(':');
+        Self::parse_header_with(data, false)
+    }
+
+    /// Like parse_header, but optionally accepts headers whose value is empty.
+    pub fn parse_header_with(data: &str, allow_empty_value: bool) -> Result<(&str, &str)> {
+        let mut splits = data.splitn(2, ':');
 
-        let key = splits.next().ok_or(Error::InvalidHeader)?;
-        let val = splits.next().ok_or(Error::InvalidHeader)?;
+        let key = splits.next().ok_or(Error::InvalidHeader)?.trim();
+        let val = splits.next().ok_or(Error::InvalidHeader)?.trim();
 
-        if key.len() == 0 || val.len() == 0 {
+        if key.len() == 0 || (val.len() == 0 && !allow_empty_value) {
             return Err(Error::InvalidHeader);
         }
 
-        Ok((key.trim(), val.trim()))
+        Ok((key, val))
     }
 
+    /// Looks up a header. Header names are case-insensitive.
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.headers.get(key)
+        self.headers.get(key).or_else(|| {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v)
+        })
+    }
+
+    /// Sets a header, replacing any existing headers with the same name.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.headers.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Removes every header with the given name, returning the value of one of them.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let mut removed = None;
+
+        self.headers.retain(|k, v| {
+            if k.eq_ignore_ascii_case(key) {
+                removed = Some(v.clone());
+                false
+            } else {
+                true
+            }
+        });
+
+        removed
+    }
+
+    /// Whether the Accept-Encoding header allows the given content coding, either by name or with
+    /// a * wildcard. Codings given a weight of q=0 are refused, as is everything when there's no
+    /// Accept-Encoding header.
+    pub fn accepts_encoding(&self, encoding: &str) -> bool {
+        let accept = match self.get("Accept-Encoding") {
+            Some(a) => a,
+            None => return false,
+        };
+
+        let mut wildcard = false;
+
+        for item in accept.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim();
+
+            let refused = params.any(|p| {
+                let p = p.trim();
+
+                p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q <= 0.0).unwrap_or(false)
+            });
+
+            // An explicit entry for the coding takes precedence over the wildcard.
+            if coding.eq_ignore_ascii_case(encoding) {
+                return !refused;
+            } else if coding == "*" {
+                wildcard = !refused;
+            }
+        }
+
+        wildcard
+    }
+
+    /// The comma separated options in the Connection header, e.g. close, keep-alive or the names of
+    /// other headers that only apply to this connection.
+    pub fn connection_options(&self) -> Vec<&str> {
+        match self.get("Connection") {
+            Some(c) => c.split(',').map(|o| o.trim()).filter(|o| o.len() > 0).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Removes the standard hop-by-hop headers along with any header the Connection header names.
+    /// Proxies must do this before forwarding a message.
+    pub fn strip_hop_by_hop(&mut self) {
+        let named = self
+            .connection_options()
+            .into_iter()
+            .map(|o| o.to_owned())
+            .collect::<Vec<_>>();
+
+        for header in HOP_BY_HOP_HEADERS.iter().map(|h| h.to_string()).chain(named) {
+            self.remove(&header);
+        }
+    }
+
+    /// The parsed Content-Length header, if present.
+    pub fn content_length(&self) -> Result<Option<usize>> {
+        match self.get("Content-Length") {
+            Some(len) => Ok(Some(len.parse::<usize>().map_err(|_| Error::InvalidHeader)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The parsed Content-Type header. None if there isn't one or it doesn't parse.
+    pub fn content_type(&self) -> Option<MediaType> {
+        MediaType::parse(self.get("Content-Type")?).ok()
     }
-}
\ No newline at end of file
+
+    /// Iterates over the headers as (name, value) pairs in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl std::iter::FromIterator<(String, String)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+/// A media type as found in a Content-Type header, e.g. `application/json; charset=utf-8`. The
+/// type, subtype and parameter names are case-insensitive, so they're kept lowercase.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaType {
+    pub main_type: String,
+    pub subtype: String,
+    pub params: HashMap<String, String>,
+}
+
+impl MediaType {
+    /// Fails with InvalidHeader if there's no type/subtype or a parameter has no `=`. Quoted
+    /// parameter values are unquoted.
+    pub fn parse(value: &str) -> Result<MediaType> {
+        let mut parts = value.split(';');
+
+        let mut essence = parts.next().unwrap_or("").trim().splitn(2, '/');
+        let main_type = essence.next().unwrap_or("").trim().to_ascii_lowercase();
+        let subtype = essence.next().unwrap_or("").trim().to_ascii_lowercase();
+
+        if main_type.is_empty() || subtype.is_empty() {
+            return Err(Error::InvalidHeader);
+        }
+
+        let mut params = HashMap::new();
+
+        for param in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim().to_ascii_lowercase();
+            let val = kv.next().ok_or(Error::InvalidHeader)?.trim();
+
+            if key.is_empty() {
+                return Err(Error::InvalidHeader);
+            }
+
+            params.insert(key, val.trim_matches('"').to_owned());
+        }
+
+        Ok(Self { main_type, subtype, params })
+    }
+
+    /// Whether this is the given type and subtype, ignoring parameters and case.
+    pub fn is(&self, main_type: &str, subtype: &str) -> bool {
+        self.main_type.eq_ignore_ascii_case(main_type) && self.subtype.eq_ignore_ascii_case(subtype)
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.params.get("charset").map(|c| c.as_str())
+    }
+}
+
+/// Whether the connection a message arrived on should stay open afterwards. HTTP/1.1 connections
+/// persist unless the Connection header says close, while HTTP/1.0 ones close unless it says
+/// keep-alive.
+pub fn should_keep_alive(version: HttpVersion, headers: &Headers) -> bool {
+    let options = headers.connection_options();
+    let has_option = |option: &str| options.iter().any(|o| o.eq_ignore_ascii_case(option));
+
+    if has_option("close") {
+        return false;
+    }
+
+    match version {
+        HttpVersion::Http1_0 => has_option("keep-alive"),
+        _ => true,
+    }
+}
+
+/// Writes all of buf to the stream. Unlike AsyncWriteExt::write_all, interrupted writes are
+/// retried, and a peer that stops taking data by accepting zero bytes or breaking the pipe gives
+/// PeerClosed so callers can tear down quietly.
+pub async fn write_fully<W>(stream: &mut W, mut buf: &[u8]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    while !buf.is_empty() {
+        match stream.write(buf).await {
+            Ok(0) => return Err(Error::PeerClosed),
+            Ok(written) => buf = &buf[written..],
+            Err(e) => match e.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::WriteZero | ErrorKind::BrokenPipe => return Err(Error::PeerClosed),
+                _ => return Err(e.into()),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{executor::block_on, io::Cursor};
+
+    use std::iter::FromIterator;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Fails the first write with the given error, then writes into the cursor a byte at a time.
+    struct FlakyWriter {
+        first_error: Option<ErrorKind>,
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            if let Some(kind) = self.first_error.take() {
+                return Poll::Ready(Err(kind.into()));
+            }
+
+            Pin::new(&mut self.data).poll_write(cx, &buf[..buf.len().min(1)])
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.data).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.data).poll_close(cx)
+        }
+    }
+
+    #[test]
+    pub fn write_fully_retries_interrupted_writes() {
+        let mut writer = FlakyWriter { first_error: Some(ErrorKind::Interrupted), data: Cursor::new(vec![]) };
+
+        block_on(write_fully(&mut writer, b"horse")).unwrap();
+
+        assert_eq!(writer.data.into_inner(), b"horse");
+    }
+
+    #[test]
+    pub fn write_fully_reports_peers_that_stop_reading() {
+        for kind in &[ErrorKind::WriteZero, ErrorKind::BrokenPipe] {
+            let mut writer = FlakyWriter { first_error: Some(*kind), data: Cursor::new(vec![]) };
+
+            assert_eq!(block_on(write_fully(&mut writer, b"horse")), Err(Error::PeerClosed));
+        }
+
+        let mut writer = FlakyWriter { first_error: Some(ErrorKind::PermissionDenied), data: Cursor::new(vec![]) };
+
+        assert_eq!(block_on(write_fully(&mut writer, b"horse")).unwrap_err().io_kind(), Some(ErrorKind::PermissionDenied));
+    }
+
+    #[test]
+    pub fn can_build_and_iterate_headers() {
+        let headers = Headers::from_iter(vec![
+            ("Content-length".to_owned(), "0".to_owned()),
+            ("Host".to_owned(), "api.giphy.com".to_owned()),
+        ]);
+
+        assert_eq!(headers.get("Host").unwrap(), "api.giphy.com");
+        assert_eq!(headers.get("host").unwrap(), "api.giphy.com");
+        assert_eq!(headers.content_length(), Ok(Some(0)));
+
+        let mut pairs = headers.iter().collect::<Vec<_>>();
+        pairs.sort();
+
+        assert_eq!(pairs, vec![("Content-length", "0"), ("Host", "api.giphy.com")]);
+    }
+
+    #[test]
+    pub fn insert_replaces_headers_regardless_of_case() {
+        let mut headers = Headers::from_iter(vec![("Content-length".to_owned(), "5".to_owned())]);
+
+        headers.insert("Content-Length", "6");
+
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec![("Content-Length", "6")]);
+        assert_eq!(headers.remove("content-length"), Some("6".to_owned()));
+        assert_eq!(headers.iter().count(), 0);
+    }
+
+    #[test]
+    pub fn can_negotiate_accept_encoding() {
+        let headers = Headers::from_iter(vec![("Accept-Encoding".to_owned(), "deflate, gzip;q=0.8".to_owned())]);
+
+        assert!(headers.accepts_encoding("gzip"));
+        assert!(!headers.accepts_encoding("br"));
+
+        let headers = Headers::from_iter(vec![("Accept-Encoding".to_owned(), "gzip;q=0, *".to_owned())]);
+
+        assert!(headers.accepts_encoding("br"));
+        assert!(!headers.accepts_encoding("gzip"));
+        assert!(!Headers::new(HashMap::new()).accepts_encoding("gzip"));
+    }
+
+    #[test]
+    pub fn can_strip_hop_by_hop_headers() {
+        let mut headers = Headers::from_iter(vec![
+            ("Connection".to_owned(), "keep-alive, X-Custom".to_owned()),
+            ("x-custom".to_owned(), "secret".to_owned()),
+            ("Keep-Alive".to_owned(), "timeout=5".to_owned()),
+            ("Transfer-Encoding".to_owned(), "chunked".to_owned()),
+            ("Host".to_owned(), "api.giphy.com".to_owned()),
+        ]);
+
+        assert_eq!(headers.connection_options(), vec!["keep-alive", "X-Custom"]);
+
+        headers.strip_hop_by_hop();
+
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec![("Host", "api.giphy.com")]);
+    }
+
+    #[test]
+    pub fn parse_header_preserves_value() {
+        assert_eq!(
+            Headers::parse_header("User-Agent: Mozilla/5.0 (X11; Linux)  "),
+            Ok(("User-Agent", "Mozilla/5.0 (X11; Linux)"))
+        );
+
+        assert_eq!(
+            Headers::parse_header("Host: api.giphy.com:443"),
+            Ok(("Host", "api.giphy.com:443"))
+        );
+    }
+
+    #[test]
+    pub fn keep_alive_follows_version_and_connection_header() {
+        let headers = |connection: Option<&str>| {
+            Headers::from_iter(connection.map(|c| ("Connection".to_owned(), c.to_owned())))
+        };
+
+        assert!(should_keep_alive(HttpVersion::Http1_1, &headers(None)));
+        assert!(should_keep_alive(HttpVersion::Http1_1, &headers(Some("keep-alive"))));
+        assert!(!should_keep_alive(HttpVersion::Http1_1, &headers(Some("close"))));
+        assert!(!should_keep_alive(HttpVersion::Http1_1, &headers(Some("Upgrade, Close"))));
+
+        assert!(!should_keep_alive(HttpVersion::Http1_0, &headers(None)));
+        assert!(should_keep_alive(HttpVersion::Http1_0, &headers(Some("Keep-Alive"))));
+        assert!(!should_keep_alive(HttpVersion::Http1_0, &headers(Some("close"))));
+        assert!(!should_keep_alive(HttpVersion::Http1_0, &headers(Some("keep-alive, close"))));
+    }
+
+    #[test]
+    pub fn can_parse_media_types() {
+        let headers = Headers::from_iter(vec![("content-type".to_owned(), "Application/JSON; charset=\"utf-8\"".to_owned())]);
+        let media_type = headers.content_type().unwrap();
+
+        assert!(media_type.is("application", "json"));
+        assert_eq!(media_type.main_type, "application");
+        assert_eq!(media_type.subtype, "json");
+        assert_eq!(media_type.charset(), Some("utf-8"));
+
+        let media_type = MediaType::parse("image/gif").unwrap();
+
+        assert!(media_type.is("image", "gif"));
+        assert!(media_type.params.is_empty());
+        assert_eq!(media_type.charset(), None);
+
+        assert_eq!(MediaType::parse("json"), Err(Error::InvalidHeader));
+        assert_eq!(MediaType::parse("text/plain; charset"), Err(Error::InvalidHeader));
+        assert_eq!(Headers::from_iter(vec![]).content_type(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+/// Running totals of the bytes that went through a CountingStream, shared by all of its clones.
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    read: AtomicUsize,
+    written: AtomicUsize,
+}
+
+impl ByteCounts {
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::SeqCst)
+    }
+
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::SeqCst)
+    }
+}
+
+/// Passes reads and writes through to the wrapped stream, tallying how many bytes went each way.
+/// Clones share their tallies, so counting still works when the stream is cloned to read and
+/// write it at the same time.
+#[derive(Clone)]
+pub struct CountingStream<S> {
+    inner: S,
+    counts: Arc<ByteCounts>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counts: Arc::new(ByteCounts::default()),
+        }
+    }
+
+    /// The tallies for this stream, which keep updating after the stream itself has moved on.
+    pub fn counts(&self) -> Arc<ByteCounts> {
+        self.counts.clone()
+    }
+
+    pub fn bytes_read(&self) -> usize {
+        self.counts.read()
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.counts.written()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> AsyncRead for CountingStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let bytes_read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        self.counts.read.fetch_add(bytes_read, Ordering::SeqCst);
+
+        Poll::Ready(Ok(bytes_read))
+    }
+}
+
+impl<S> AsyncWrite for CountingStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let bytes_written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+
+        self.counts.written.fetch_add(bytes_written, Ordering::SeqCst);
+
+        Poll::Ready(Ok(bytes_written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_support::TestTransport;
+
+    use futures::{executor::LocalPool, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    pub fn tallies_bytes_both_ways() {
+        let (client, mut server) = TestTransport::pair();
+        let mut counted = CountingStream::new(client);
+
+        LocalPool::default().run_until(async {
+            counted.write_all(b"hello, world").await.unwrap();
+            server.write_all(b"hi!").await.unwrap();
+            server.close().await.unwrap();
+
+            let mut reply = vec![];
+            counted.read_to_end(&mut reply).await.unwrap();
+
+            assert_eq!(reply, b"hi!");
+        });
+
+        assert_eq!(counted.bytes_written(), 12);
+        assert_eq!(counted.bytes_read(), 3);
+    }
+
+    #[test]
+    pub fn clones_share_tallies() {
+        let (client, _server) = TestTransport::pair();
+        let counted = CountingStream::new(client);
+        let counts = counted.counts();
+
+        LocalPool::default().run_until(async {
+            counted.clone().write_all(b"abc").await.unwrap();
+            counted.clone().write_all(b"de").await.unwrap();
+        });
+
+        assert_eq!(counts.written(), 5);
+        assert_eq!(counted.bytes_written(), 5);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::response::Status;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -37,6 +39,24 @@ pub enum Error {
     /// The current header is too long.
     HeaderTooLong,
 
+    /// The request has more header lines than we're willing to read.
+    TooManyHeaders,
+
+    /// The request has no Host header where one is required.
+    MissingHost,
+
+    /// The request body is longer than we're willing to read.
+    BodyTooLong,
+
+    /// The request as a whole is larger than we're willing to read.
+    RequestTooLarge,
+
+    /// The Content-Length header disagrees with the length of the body that was written.
+    ContentLengthMismatch {
+        declared: usize,
+        written: usize,
+    },
+
     /// The header is invalid.
     InvalidHeader,
 
@@ -62,6 +82,37 @@ pub enum Error {
     MissingPort,
 
     DnsLookupFailed,
+
+    /// The peer took longer than we allow.
+    Timeout,
+
+    /// The upstream only resolved to addresses we refuse to connect to.
+    ForbiddenUpstream,
+
+    /// The peer stopped accepting data partway through a write.
+    PeerClosed,
+}
+
+impl Error {
+    /// The kind of the underlying IO error, so callers can branch on things like timeouts and resets.
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Self::IOError(wrapper) => Some(wrapper.err.kind()),
+            _ => None,
+        }
+    }
+
+    /// The status to answer a request with when reading it failed with this error.
+    pub fn to_status(&self) -> Status {
+        match self {
+            Self::HeadersSectionTooLong | Self::HeaderTooLong | Self::TooManyHeaders => Status::RequestHeaderFieldsTooLarge,
+            Self::StartLineExceedsMaxLength => Status::UriTooLong,
+            Self::RequestTooLarge | Self::BodyTooLong => Status::PayloadTooLarge,
+            Self::Timeout => Status::RequestTimeout,
+            Self::InvalidMethod(_) => Status::NotImplemented,
+            _ => Status::BadRequest,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -72,10 +123,39 @@ impl From<std::io::Error> for Error {
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::TooManyHeaders => write!(f, "Too many headers."),
+            Self::MissingHost => write!(f, "The request has no Host header."),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
 impl std::error::Error for Error {
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn exposes_io_error_kind() {
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+
+        assert_eq!(err.io_kind(), Some(std::io::ErrorKind::TimedOut));
+        assert_eq!(Error::InvalidHeader.io_kind(), None);
+    }
+
+    #[test]
+    pub fn maps_errors_to_statuses() {
+        assert_eq!(Error::TooManyHeaders.to_status(), Status::RequestHeaderFieldsTooLarge);
+        assert_eq!(Error::MissingHost.to_status(), Status::BadRequest);
+        assert_eq!(Error::StartLineExceedsMaxLength.to_status(), Status::UriTooLong);
+        assert_eq!(Error::Timeout.to_status(), Status::RequestTimeout);
+        assert_eq!(Error::InvalidMethod("BREW".to_owned()).to_status(), Status::NotImplemented);
+
+        assert_eq!(Error::TooManyHeaders.to_string(), "Too many headers.");
+        assert_eq!(Error::MissingHost.to_string(), "The request has no Host header.");
+    }
+}
@@ -1,9 +1,19 @@
+mod channel_body;
 mod common;
+mod counting_stream;
 mod error;
+mod limited_reader;
+mod rate_limit;
 pub mod request;
 pub mod response;
 mod server;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
+pub use channel_body::ChannelBody;
+pub use counting_stream::{ByteCounts, CountingStream};
 pub use error::{Error, Result};
-pub use server::{HttpServer, HttpServerBuilder};
+pub use limited_reader::LimitedReader;
+pub use rate_limit::{log_rate_limited, RateLimiter};
+pub use server::{log_access, ConnInfo, HttpServer, HttpServerBuilder};
 pub use common::*;
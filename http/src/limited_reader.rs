@@ -0,0 +1,92 @@
+use futures::{io::AsyncRead, ready};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Hands out at most a fixed number of bytes from the wrapped reader. A stream that ends at or
+/// before the limit reads normally, but one with data past the limit fails with InvalidData rather
+/// than quietly stopping, so whoever is reading knows the peer sent too much.
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+
+    /// How many more bytes we'll hand out.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> AsyncRead for LimitedReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if self.remaining == 0 {
+            // Tells a stream that ends right at the limit apart from one that carries on past it.
+            let mut probe = [0; 1];
+
+            return match ready!(Pin::new(&mut self.inner).poll_read(cx, &mut probe))? {
+                0 => Poll::Ready(Ok(0)),
+                _ => Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Read past the limit"))),
+            };
+        }
+
+        let max = std::cmp::min(buf.len(), self.remaining);
+        let bytes_read = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..max]))?;
+
+        self.remaining -= bytes_read;
+
+        Poll::Ready(Ok(bytes_read))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_std::io::Cursor;
+    use futures::{executor::LocalPool, AsyncReadExt};
+
+    #[test]
+    pub fn reads_up_to_the_limit() {
+        let mut data = vec![];
+
+        LocalPool::default().run_until(async {
+            LimitedReader::new(Cursor::new("hello"), 5).read_to_end(&mut data).await.unwrap();
+        });
+
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    pub fn fails_when_reading_past_the_limit() {
+        let mut reader = LimitedReader::new(Cursor::new("hello world"), 5);
+        let mut data = vec![];
+
+        let result = LocalPool::default().run_until(async {
+            reader.read_to_end(&mut data).await
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(data, b"hello");
+        assert_eq!(reader.remaining(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+use log::{log, Level};
+use once_cell::sync::Lazy;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often log_rate_limited lets through a message with the same key.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+static LOG_LIMITER: Lazy<RateLimiter> = Lazy::new(|| RateLimiter::new(LOG_INTERVAL));
+
+/// Lets through at most one event per key per interval, counting the ones it holds back.
+pub struct RateLimiter {
+    interval: Duration,
+    entries: Mutex<HashMap<&'static str, (Instant, usize)>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an event for key at now. Returns how many events were suppressed since the last
+    /// one let through if this one should be let through, or None if it should be suppressed.
+    pub fn check(&self, key: &'static str, now: Instant) -> Option<usize> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get_mut(key) {
+            Some((last, suppressed)) if now.saturating_duration_since(*last) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((last, suppressed)) => {
+                let count = *suppressed;
+                *last = now;
+                *suppressed = 0;
+                Some(count)
+            }
+            None => {
+                entries.insert(key, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Logs the message unless one with the same key was logged in the last 10 seconds. Use this on
+/// paths a flood of connections or a misbehaving client can hit thousands of times a second. The
+/// next line let through says how many were suppressed.
+pub fn log_rate_limited(key: &'static str, level: Level, message: fmt::Arguments) {
+    match LOG_LIMITER.check(key, Instant::now()) {
+        Some(0) => log!(level, "{}", message),
+        Some(suppressed) => log!(level, "{} ({} similar messages suppressed)", message, suppressed),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn suppresses_repeats_within_the_interval() {
+        let limiter = RateLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("reset", start), Some(0));
+
+        for i in 1..100 {
+            assert_eq!(limiter.check("reset", start + Duration::from_millis(i)), None);
+        }
+
+        // Other keys are limited separately.
+        assert_eq!(limiter.check("accept", start), Some(0));
+
+        assert_eq!(limiter.check("reset", start + Duration::from_secs(10)), Some(99));
+        assert_eq!(limiter.check("reset", start + Duration::from_secs(11)), None);
+    }
+
+    #[test]
+    pub fn floods_produce_a_bounded_number_of_lines() {
+        crate::test_support::capture_logs();
+
+        for _ in 0..1000 {
+            log_rate_limited("rate-limit-test", Level::Warn, format_args!("rate limited flood"));
+        }
+
+        let lines = crate::test_support::captured_logs()
+            .into_iter()
+            .filter(|l| l.contains("rate limited flood"))
+            .count();
+
+        assert_eq!(lines, 1);
+    }
+}
@@ -1,15 +1,20 @@
 use async_std::io::{ReadExt};
-use futures::io::{AsyncWrite, AsyncWriteExt};
+use futures::io::AsyncWrite;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use url::Url;
 
 use std::collections::HashMap;
 
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use crate::{
     Error,
+    LimitedReader,
     Result,
     common::{
+        write_fully,
         HttpVersion,
         Headers,
     }
@@ -43,11 +48,10 @@ impl Method {
             _ => Err(Error::InvalidMethod(data.to_owned())),
         }
     }
-}
 
-impl std::fmt::Display for Method {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let as_str = match self {
+    /// The inverse of Method::parse.
+    pub fn as_str(&self) -> &'static str {
+        match self {
             Self::GET => "GET",
             Self::POST => "POST",
             Self::PUT => "PUT",
@@ -57,19 +61,94 @@ impl std::fmt::Display for Method {
             Self::OPTIONS => "OPTIONS",
             Self::TRACE => "TRACE",
             Self::PATCH => "PATCH",
-        };
+        }
+    }
+}
 
-        write!(f, "{}", as_str)
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
     }
 }
 
 /// The host and port part of a url. Should only be used with OPTIONS verb
 #[derive(Debug, PartialEq, Clone)]
 pub struct Authority {
+    /// A host name or IP address. IPv6 addresses are stored without their brackets.
     pub domain: String,
     pub port: Option<u16>,
 }
 
+impl std::fmt::Display for Authority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only IPv6 literals contain colons and they need brackets to keep them apart from the port.
+        if self.domain.contains(':') {
+            write!(f, "[{}]", self.domain)?;
+        } else {
+            write!(f, "{}", self.domain)?;
+        }
+
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Authority {
+    /// Parses a host with an optional port, e.g. the value of a Host header. Unlike authority-form
+    /// targets, the host may be a single label such as localhost. IPv6 literals must be bracketed.
+    pub fn parse(data: &str) -> Result<Authority> {
+        if data.starts_with('[') {
+            return Target::parse_ipv6_authority(data);
+        }
+
+        let (domain, port) = match data.rfind(':') {
+            Some(colon) => (&data[..colon], Some(&data[colon + 1..])),
+            None => (data, None),
+        };
+
+        if !HOST_REGEX.is_match(domain) {
+            return Err(Error::InvalidTarget);
+        }
+
+        let port = match port {
+            Some(p) if p.len() > 0 && p.chars().all(|c| c.is_ascii_digit()) => {
+                Some(u16::from_str_radix(p, 10).map_err(|_| Error::InvalidTarget)?)
+            },
+            Some(_) => return Err(Error::InvalidTarget),
+            None => None,
+        };
+
+        Ok(Authority {
+            domain: domain.to_owned(),
+            port,
+        })
+    }
+
+    /// The host:port string to resolve and connect to, with IPv6 literals bracketed. Fails with
+    /// MissingPort rather than guessing one.
+    pub fn to_connect_string(&self) -> Result<String> {
+        match self.port {
+            Some(_) => Ok(self.to_string()),
+            None => Err(Error::MissingPort),
+        }
+    }
+
+    /// Lowercases the domain and drops the trailing dot of a fully qualified name, so equivalent
+    /// hosts compare equal.
+    fn normalized(mut self) -> Self {
+        self.domain.make_ascii_lowercase();
+
+        if self.domain.ends_with('.') {
+            self.domain.pop();
+        }
+
+        self
+    }
+}
+
 /// A set of limits on HTTP requests to mitigate slowloris attacks.
 #[derive(Debug, Clone, Copy)]
 pub struct ParseOptions {
@@ -78,6 +157,22 @@ pub struct ParseOptions {
     max_headers_section_len: usize,
     max_header_len: usize,
     max_body_len: usize,
+
+    /// Maximum number of header lines, counting any blank lines sent before the start line.
+    max_header_count: usize,
+
+    /// Maximum number of bytes the start line, headers and body may occupy together.
+    max_total_request_len: usize,
+
+    /// Accept headers with an empty value, e.g. `X-Empty:`, rather than rejecting the request.
+    allow_empty_header_values: bool,
+
+    /// Reject a CR that isn't followed by LF. When off, such a CR is kept as part of the line.
+    strict_line_endings: bool,
+
+    /// How long the start line and headers may take to arrive, from their first byte to the blank
+    /// line ending them.
+    max_header_phase: Option<Duration>,
 }
 
 impl Default for ParseOptions {
@@ -87,6 +182,11 @@ impl Default for ParseOptions {
             max_headers_section_len: 16 * 1024,
             max_header_len: 1024,
             max_body_len: 2 * 1024 * 1024,
+            max_header_count: 100,
+            max_total_request_len: 4 * 1024 * 1024,
+            allow_empty_header_values: false,
+            strict_line_endings: true,
+            max_header_phase: None,
         }
     }
 }
@@ -108,8 +208,90 @@ impl ParseOptions {
     pub fn max_header_len(&self) -> usize {
         self.max_header_len
     }
+
+    pub fn max_body_len(&self) -> usize {
+        self.max_body_len
+    }
+
+    pub fn max_total_request_len(&self) -> usize {
+        self.max_total_request_len
+    }
+
+    pub fn max_header_count(&self) -> usize {
+        self.max_header_count
+    }
+
+    /// Bounds how many lines we'll process, however short they are. Defaults to 100.
+    pub fn with_max_header_count(self, count: usize) -> Self {
+        Self {
+            max_header_count: count,
+            ..self
+        }
+    }
+
+    /// Bounds the total size of a request across all of its sections.
+    pub fn with_max_total_request_len(self, len: usize) -> Self {
+        Self {
+            max_total_request_len: len,
+            ..self
+        }
+    }
+
+    pub fn allow_empty_header_values(&self) -> bool {
+        self.allow_empty_header_values
+    }
+
+    /// Some clients send headers with empty values. We reject these by default.
+    pub fn with_allow_empty_header_values(self, allow: bool) -> Self {
+        Self {
+            allow_empty_header_values: allow,
+            ..self
+        }
+    }
+
+    pub fn strict_line_endings(&self) -> bool {
+        self.strict_line_endings
+    }
+
+    /// Some legacy clients send bare CRs in odd places. We reject these by default, but turning
+    /// this off treats them as ordinary characters, which helps when debugging such clients.
+    pub fn with_strict_line_endings(self, strict: bool) -> Self {
+        Self {
+            strict_line_endings: strict,
+            ..self
+        }
+    }
+
+    pub fn max_header_phase(&self) -> Option<Duration> {
+        self.max_header_phase
+    }
+
+    /// Bounds the time spent receiving the start line and headers. Unlike a per-read timeout, a
+    /// client can't stretch this out by trickling bytes in. Unbounded by default.
+    pub fn with_max_header_phase(self, budget: Duration) -> Self {
+        Self {
+            max_header_phase: Some(budget),
+            ..self
+        }
+    }
 }
 
+/// No target we'd accept is anywhere near this long, whatever the ParseOptions. Checked before
+/// running the regex over it.
+const MAX_TARGET_LEN: usize = 64 * 1024;
+
+/// Matches authority-form targets, e.g. api.giphy.com:443.
+static AUTHORITY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    // Labels may carry percent escapes and the domain may be fully qualified with a trailing dot.
+    // Callers comparing domains should normalize them first.
+    Regex::new(r"^((\d|[[:alpha:]]|%[[:xdigit:]]{2})+\.)+(\d|[[:alpha:]]|%[[:xdigit:]]{2})+\.?(:\d+)?$").unwrap()
+});
+
+/// Matches host names, which unlike authority-form targets may be a single label, e.g. localhost.
+static HOST_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^((\d|[[:alpha:]]|-|%[[:xdigit:]]{2})+\.)*(\d|[[:alpha:]]|-|%[[:xdigit:]]{2})+\.?$").unwrap()
+});
+
 /// The second field in the start line.
 /// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Messages
 #[derive(Debug, PartialEq)]
@@ -132,7 +314,7 @@ pub enum Target {
 
 impl Target {
     pub fn parse(target_str: &str) -> Result<Target> {
-        if target_str.len() == 0 {
+        if target_str.len() == 0 || target_str.len() > MAX_TARGET_LEN {
             return Err(Error::InvalidTarget);
         }
 
@@ -149,10 +331,11 @@ impl Target {
             };
         }
 
-        let authority_regex =
-            regex::Regex::from_str(r"^((\d|[[:alpha:]])+\.)+(\d|[[:alpha:]])+(:\d+)?$").unwrap();
+        if target_str.starts_with('[') {
+            return Self::parse_ipv6_authority(target_str).map(Self::Authority);
+        }
 
-        if authority_regex.is_match(target_str) {
+        if AUTHORITY_REGEX.is_match(target_str) {
             let mut splits = target_str.split(":");
 
             // Should be unreachable, but panicking here is probably worse than just returning
@@ -174,6 +357,45 @@ impl Target {
             Url::from_str(target_str).map_err(|_| Error::InvalidTarget)?,
         ));
     }
+
+    /// Parses an authority with a bracketed IPv6 literal, e.g. [::1]:443.
+    fn parse_ipv6_authority(target_str: &str) -> Result<Authority> {
+        let end = target_str.find(']').ok_or(Error::InvalidTarget)?;
+        let domain = &target_str[1..end];
+
+        std::net::Ipv6Addr::from_str(domain).map_err(|_| Error::InvalidTarget)?;
+
+        let port = match &target_str[end + 1..] {
+            "" => None,
+            p if p.starts_with(':') && p.len() > 1 && p[1..].chars().all(|c| c.is_ascii_digit()) => {
+                Some(u16::from_str_radix(&p[1..], 10).map_err(|_| Error::InvalidTarget)?)
+            },
+            _ => return Err(Error::InvalidTarget),
+        };
+
+        Ok(Authority {
+            domain: domain.to_owned(),
+            port,
+        })
+    }
+
+    /// The percent-decoded query parameters of a path or URL target. When a key repeats, the first
+    /// value wins. Other targets have no query.
+    pub fn query_params(&self) -> HashMap<String, String> {
+        let query = match self {
+            Self::Path(p) => p.splitn(2, '?').nth(1),
+            Self::Url(u) => u.query(),
+            _ => None,
+        };
+
+        let mut params = HashMap::new();
+
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            params.entry(k.into_owned()).or_insert_with(|| v.into_owned());
+        }
+
+        params
+    }
 }
 
 impl std::fmt::Display for Target {
@@ -181,21 +403,13 @@ impl std::fmt::Display for Target {
         match self {
             Self::Path(s) => write!(f, "{}", s),
             Self::Url(u) => write!(f, "{}", u),
-            Self::Authority(a) => {
-                write!(f, "{}", a.domain)?;
-
-                if let Some(port) = a.port {
-                    write!(f, ":{}", port)?
-                }
-
-                Ok(())
-            },
+            Self::Authority(a) => write!(f, "{}", a),
             Self::Glob => write!(f, "*"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct StartLine {
     pub method: Method,
     pub target: Target,
@@ -211,25 +425,141 @@ impl std::fmt::Display for StartLine {
 }
 
 impl StartLine {
+    /// Parses a start line. Tolerates runs of spaces between and around the tokens as some lax clients
+    /// send them, but tabs aren't treated as separators.
     pub fn parse(data: &str) -> Result<Self> {
-        let mut splits = data.split(' ');
+        let mut splits = data.split(' ').filter(|s| s.len() > 0);
 
         let method = Method::parse(splits.next().ok_or(Error::InvalidStartLine)?)?;
         let target = Target::parse(splits.next().ok_or(Error::InvalidStartLine)?)?;
         let version = HttpVersion::parse(splits.next().ok_or(Error::InvalidStartLine)?)?;
 
+        if splits.next().is_some() {
+            return Err(Error::InvalidStartLine);
+        }
+
+        if !Self::target_form_allowed(&method, &target) {
+            return Err(Error::InvalidTarget);
+        }
+
         Ok(StartLine {
             method,
             target,
             version,
         })
     }
+
+    /// Whether requests with this method conventionally carry a body. Only a hint: what the headers
+    /// declare decides whether one actually follows, see Request::expects_body.
+    pub fn expects_body(&self) -> bool {
+        match self.method {
+            Method::GET | Method::HEAD | Method::CONNECT | Method::TRACE => false,
+            _ => true,
+        }
+    }
+
+    /// CONNECT only takes authority form and no other method can use it. * only makes sense for
+    /// OPTIONS. Everything else may use origin or absolute form, the latter being what clients send
+    /// to proxies.
+    fn target_form_allowed(method: &Method, target: &Target) -> bool {
+        match (method, target) {
+            (Method::CONNECT, Target::Authority(_)) => true,
+            (Method::CONNECT, _) | (_, Target::Authority(_)) => false,
+            (Method::OPTIONS, Target::Glob) => true,
+            (_, Target::Glob) => false,
+            _ => true,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Request {
     pub start_line: StartLine,
     pub headers: Headers,
+
+    /// The body, if any. Request::parse leaves this empty; use Request::read_body to fill it in.
+    pub body: Option<Vec<u8>>,
+
+    /// The number of bytes the start line and headers occupied on the wire.
+    head_len: usize,
+
+    /// Bytes Request::parse read past the end of the headers, e.g. the start of a body or tunneled
+    /// data a client sent without waiting for a response.
+    leftover: Vec<u8>,
+}
+
+/// How much Request::parse reads from the stream at a time.
+const READ_BUFFER_LEN: usize = 1024;
+
+/// Reads a stream a buffer at a time but hands it out a byte at a time. When parsing stops, whatever
+/// remains in the buffer belongs to what follows the headers.
+struct ByteReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    len: usize,
+
+    /// How long reads may take in total, counted from the first byte.
+    budget: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl<R> ByteReader<R>
+where
+    R: ReadExt + Unpin,
+{
+    fn new(inner: R, budget: Option<Duration>) -> Self {
+        Self {
+            inner,
+            buffer: vec![0; READ_BUFFER_LEN],
+            pos: 0,
+            len: 0,
+            budget,
+            deadline: None,
+        }
+    }
+
+    /// The next byte of the stream or None at the end of the stream, which is the first read that
+    /// returns no bytes.
+    async fn next(&mut self) -> Result<Option<u8>> {
+        if self.pos == self.len {
+            self.len = match self.deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+
+                    async_std::future::timeout(remaining, self.inner.read(&mut self.buffer))
+                        .await
+                        .map_err(|_| Error::Timeout)??
+                },
+                None => self.inner.read(&mut self.buffer).await?,
+            };
+            self.pos = 0;
+
+            if self.deadline.is_none() && self.len > 0 {
+                self.deadline = self.budget.map(|budget| Instant::now() + budget);
+            }
+
+            if self.len == 0 {
+                return Ok(None);
+            }
+        }
+
+        let byte = self.buffer[self.pos];
+        self.pos += 1;
+
+        Ok(Some(byte))
+    }
+
+    /// Puts back the byte the last call to next returned, so the next call returns it again. Only
+    /// valid straight after next returned a byte.
+    fn unread(&mut self) {
+        self.pos -= 1;
+    }
+
+    /// The bytes read from the stream but not yet handed out.
+    fn into_leftover(self) -> Vec<u8> {
+        self.buffer[self.pos..self.len].to_vec()
+    }
 }
 
 enum RequestParseStateMachine {
@@ -242,42 +572,71 @@ impl Request {
     /// attacks by aborting if reading too many characters in a given section of the request. Does not
     /// assume newlines will come before the limit is reached. In the event of failure, the stream will
     /// effectively be closed.
+    ///
+    /// The stream is read in chunks, so bytes following the headers may already have been consumed.
+    /// These are kept with the request; see Request::leftover.
+    ///
+    /// Lines may end with LF alone rather than CRLF, and a request doing so throughout parses the
+    /// same as its CRLF equivalent. Mixing the two within a request is fine too.
+    ///
+    /// A read returning no bytes is the end of the stream, as AsyncRead defines it, and we don't read
+    /// again after one. Before a complete start line that fails with UnexpectedEndOfStream. After
+    /// it, the request ends with whatever headers arrived. A stream that returns 0 and then carries
+    /// on is broken, and anything it sends afterwards isn't part of this request.
     /// TODO: use a timer to measure request bandwidth and enforce a minimum before just erroring.
     /// TODO: We assume enforce that the start line and headers are ASCII. The internet suggests this is correct,
     /// but I'm not sure and leaves an open question around how HTTP handles Internationalized Domain Names
-    pub async fn parse<R>(mut data: R, parse_options: &ParseOptions) -> Result<Self>
+    pub async fn parse<R>(data: R, parse_options: &ParseOptions) -> Result<Self>
     where
         R: ReadExt + Unpin,
     {
-        let mut read_buffer = vec![0; 1];
+        let mut reader = ByteReader::new(data, parse_options.max_header_phase());
         let mut current_line = vec![];
+        let mut request_len = 0;
+        let mut line_count = 0;
 
         let mut state = RequestParseStateMachine::ParseStartLine;
 
         loop {
-            let num_stream_bytes = data.read(&mut read_buffer).await?;
-
-            if num_stream_bytes == 0 {
-                return Err(Error::UnexpectedEndOfStream);
-            }
+            // Some clients close the stream straight after the last header rather than sending the
+            // blank line. Once we have a start line, treat that as the end of the headers.
+            let mut byte = match reader.next().await? {
+                Some(b) => b,
+                None => return match state {
+                    RequestParseStateMachine::ParseStartLine => Err(Error::UnexpectedEndOfStream),
+                    RequestParseStateMachine::ParseHeaders(_, start_line, mut headers) => {
+                        if current_line.len() > 0 {
+                            // The unterminated last line still counts toward the limit.
+                            line_count += 1;
+
+                            if line_count > parse_options.max_header_count() {
+                                return Err(Error::TooManyHeaders);
+                            }
+
+                            let current_line_str = std::str::from_utf8(&current_line).map_err(|_| Error::InvalidEncoding)?;
+                            let (key, val) = Headers::parse_header_with(current_line_str, parse_options.allow_empty_header_values())?;
+
+                            headers.insert(key.to_owned(), val.to_owned());
+                        }
 
-            // Check that we haven't exceeded limits
-            match state {
-                RequestParseStateMachine::ParseStartLine => {
-                    if current_line.len() > parse_options.max_start_line_len() {
-                        return Err(Error::StartLineExceedsMaxLength);
-                    }
-                }
-                RequestParseStateMachine::ParseHeaders(ref size, ref _s, ref _h) => {
-                    if parse_options.max_headers_section_len() < *size + current_line.len() {
-                        return Err(Error::HeadersSectionTooLong);
-                    } else if current_line.len() > parse_options.max_header_len() {
-                        return Err(Error::HeaderTooLong);
+                        Ok(Self {
+                            start_line,
+                            headers: Headers::new(headers),
+                            body: None,
+                            head_len: request_len,
+                            leftover: vec![],
+                        })
                     }
-                }
+                },
             };
 
-            if !read_buffer[0].is_ascii() {
+            request_len += 1;
+
+            if request_len > parse_options.max_total_request_len() {
+                return Err(Error::RequestTooLarge);
+            }
+
+            if !byte.is_ascii() {
                 return Err(Error::InvalidEncoding);
             }
 
@@ -286,24 +645,43 @@ impl Request {
             // put CR into headers?
             // Since The next character must be newline, we don't need to recheck the line_size
             // because you can't put more than one CR in a row in the buffer.
-            if read_buffer[0] == b'\r' {
-                let num_stream_bytes = data.read(&mut read_buffer).await?;
+            // Without strict line endings, a CR that isn't followed by LF is kept as an ordinary
+            // character and whatever followed it is read again on the next pass.
+            if byte == b'\r' {
+                match reader.next().await? {
+                    Some(b'\n') => {
+                        byte = b'\n';
 
-                if num_stream_bytes == 0 {
-                    return Err(Error::UnexpectedEndOfStream);
-                }
+                        request_len += 1;
 
-                if read_buffer[0] != b'\n' {
-                    return Err(Error::UnexpectedCR);
+                        if request_len > parse_options.max_total_request_len() {
+                            return Err(Error::RequestTooLarge);
+                        }
+                    },
+                    Some(_) if !parse_options.strict_line_endings() => reader.unread(),
+                    None if !parse_options.strict_line_endings() => {},
+                    Some(_) => return Err(Error::UnexpectedCR),
+                    None => return Err(Error::UnexpectedEndOfStream),
                 }
             }
             
-            if read_buffer[0] == b'\n' {
-                // We've validated all the characters in the stream are ASCII, so the below is
-                // sound.
-                let current_line_str = unsafe { std::str::from_utf8_unchecked(&current_line) };
+            if byte == b'\n' {
+                // We've already rejected anything that isn't ASCII, so this can't fail, but we'd
+                // rather an error than undefined behaviour if that ever changes.
+                let current_line_str = std::str::from_utf8(&current_line).map_err(|_| Error::InvalidEncoding)?;
 
                 state = match state {
+                    // Clients may send blank lines before the start line, which we skip. They
+                    // count towards the header limit so they can't go on forever.
+                    RequestParseStateMachine::ParseStartLine if current_line_str.is_empty() => {
+                        line_count += 1;
+
+                        if line_count > parse_options.max_header_count() {
+                            return Err(Error::TooManyHeaders);
+                        }
+
+                        RequestParseStateMachine::ParseStartLine
+                    }
                     RequestParseStateMachine::ParseStartLine => {
                         RequestParseStateMachine::ParseHeaders(
                             0,
@@ -324,11 +702,20 @@ impl Request {
                                 Self {
                                     start_line,
                                     headers: Headers::new(headers),
+                                    body: None,
+                                    head_len: request_len,
+                                    leftover: reader.into_leftover(),
                                 },
                             );
                         }
 
-                        let (key, val) = Headers::parse_header(&current_line_str)?;
+                        line_count += 1;
+
+                        if line_count > parse_options.max_header_count() {
+                            return Err(Error::TooManyHeaders);
+                        }
+
+                        let (key, val) = Headers::parse_header_with(&current_line_str, parse_options.allow_empty_header_values())?;
 
                         headers.insert(key.to_owned(), val.to_owned());
 
@@ -338,34 +725,275 @@ impl Request {
 
                 current_line.clear();
             } else {
-                current_line.push(read_buffer[0]);
+                current_line.push(byte);
+
+                // Check limits on the byte that crosses them, rather than reading on. A line of
+                // exactly the maximum length is fine and the line ending doesn't count towards it.
+                match state {
+                    RequestParseStateMachine::ParseStartLine => {
+                        if current_line.len() > parse_options.max_start_line_len() {
+                            return Err(Error::StartLineExceedsMaxLength);
+                        }
+                    }
+                    RequestParseStateMachine::ParseHeaders(ref size, ref _s, ref _h) => {
+                        if parse_options.max_headers_section_len() < *size + current_line.len() {
+                            return Err(Error::HeadersSectionTooLong);
+                        } else if current_line.len() > parse_options.max_header_len() {
+                            return Err(Error::HeaderTooLong);
+                        }
+                    }
+                };
             }
         }
     }
 
-    /// Writes this HTTP request into the given stream
+    /// Bytes that followed the headers which Request::parse already read from the stream. Anything
+    /// consuming the rest of the stream must handle these first. Request::read_body does so.
+    pub fn leftover(&self) -> &[u8] {
+        &self.leftover
+    }
+
+    /// Takes the leftover bytes, e.g. to forward them down a tunnel.
+    pub fn take_leftover(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.leftover)
+    }
+
+    /// Adds bytes that were read from the stream after those already in leftover, so whoever takes
+    /// over the stream sees them in order.
+    pub(crate) fn extend_leftover(&mut self, bytes: &[u8]) {
+        self.leftover.extend_from_slice(bytes);
+    }
+
+    /// Whether the client sent Expect: 100-continue and is waiting for a 100 Continue before sending
+    /// the body. Only HTTP/1.1 clients do this; HTTP/1.0 ones don't know the mechanism.
+    pub fn expects_continue(&self) -> bool {
+        if self.start_line.version != HttpVersion::Http1_1 {
+            return false;
+        }
+
+        match self.headers.get("Expect") {
+            Some(e) => e.eq_ignore_ascii_case("100-continue"),
+            None => false,
+        }
+    }
+
+    /// The host this request is for. Targets in authority or absolute form name it themselves and
+    /// take precedence over the Host header, as RFC 7230 section 5.4 requires. Otherwise it comes
+    /// from the Host header. None if neither names a valid host.
+    pub fn host(&self) -> Option<Authority> {
+        let authority = match &self.start_line.target {
+            Target::Authority(a) => a.clone(),
+            Target::Url(url) => Authority {
+                domain: match url.host()? {
+                    url::Host::Domain(d) => d.to_owned(),
+                    url::Host::Ipv4(ip) => ip.to_string(),
+                    url::Host::Ipv6(ip) => ip.to_string(),
+                },
+                port: url.port_or_known_default(),
+            },
+            Target::Path(_) | Target::Glob => Authority::parse(self.headers.get("Host")?.trim()).ok()?,
+        };
+
+        Some(authority.normalized())
+    }
+
+    /// Whether a body follows the headers, which is what the framing headers say regardless of the
+    /// method. A Content-Length we can't parse counts as declaring one, since we can't tell where
+    /// the request ends.
+    pub fn expects_body(&self) -> bool {
+        let has_length = match self.headers.content_length() {
+            Ok(None) | Ok(Some(0)) => false,
+            _ => true,
+        };
+
+        has_length || self.headers.get("Transfer-Encoding").is_some()
+    }
+
+    /// The body length the Content-Length header declares, checked against the limits in
+    /// parse_options. Lets callers refuse an oversized body before asking the client to send it.
+    pub fn declared_body_len(&self, parse_options: &ParseOptions) -> Result<Option<usize>> {
+        let len = match self.headers.content_length()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        if len > parse_options.max_body_len() {
+            return Err(Error::BodyTooLong);
+        }
+
+        if self.head_len + len > parse_options.max_total_request_len() {
+            return Err(Error::RequestTooLarge);
+        }
+
+        Ok(Some(len))
+    }
+
+    /// Reads the body announced by the Content-Length header from the remainder of a stream that
+    /// Request::parse consumed the headers from. Without a Content-Length, the request has no body.
+    /// Does nothing if the body was already read, as HttpServer does before calling handlers.
+    pub async fn read_body<R>(&mut self, data: R, parse_options: &ParseOptions) -> Result<()>
+    where
+        R: ReadExt + Unpin,
+    {
+        if self.body.is_some() {
+            return Ok(());
+        }
+
+        let len = match self.declared_body_len(parse_options)? {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+
+        let mut body = vec![0; len];
+
+        // Request::parse may have already read the start of the body.
+        let buffered = std::cmp::min(len, self.leftover.len());
+        body[..buffered].copy_from_slice(&self.leftover[..buffered]);
+        self.leftover.drain(..buffered);
+
+        let mut data = LimitedReader::new(data, len - buffered);
+
+        data.read_exact(&mut body[buffered..]).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEndOfStream,
+            _ => Error::from(e),
+        })?;
+
+        self.body = Some(body);
+
+        Ok(())
+    }
+
+    /// Writes this HTTP request into the given stream. If there's a body, we write a Content-Length
+    /// header describing it in place of any the headers contain.
     pub async fn write_to_stream<S>(&self, stream: &mut S) -> Result<()> 
         where S: AsyncWrite + Unpin
     {
-        stream.write(format!("{}\r\n", self.start_line).as_bytes()).await?;
+        write_fully(stream, format!("{}\r\n", self.start_line).as_bytes()).await?;
         
         for (k, v) in &self.headers.headers {
-            stream.write(format!("{}:{}\r\n", k, v).as_bytes()).await?;
+            if self.body.is_some() && k.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+
+            write_fully(stream, format!("{}:{}\r\n", k, v).as_bytes()).await?;
+        }
+
+        if let Some(body) = &self.body {
+            write_fully(stream, format!("Content-Length:{}\r\n", body.len()).as_bytes()).await?;
         }
         
-        stream.write(b"\r\n").await?;
+        write_fully(stream, b"\r\n").await?;
+
+        if let Some(body) = &self.body {
+            write_fully(stream, body).await?;
+        }
 
         Ok(())
     }
 }
 
+/// Builds a Request for sending to a server. Defaults to HTTP/1.1 with no headers.
+pub struct RequestBuilder {
+    method: Option<Method>,
+    target: Option<String>,
+    version: HttpVersion,
+    headers: HashMap<String, String>,
+    body: Option<Vec<u8>>,
+}
+
+impl RequestBuilder {
+    pub fn new() -> Self {
+        Self {
+            method: None,
+            target: None,
+            version: HttpVersion::Http1_1,
+            headers: HashMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn method(self, method: Method) -> Self {
+        Self {
+            method: Some(method),
+            ..self
+        }
+    }
+
+    /// The request target. This gets parsed with Target::parse when the request is built.
+    pub fn target(self, target: &str) -> Self {
+        Self {
+            target: Some(target.to_owned()),
+            ..self
+        }
+    }
+
+    pub fn version(self, version: HttpVersion) -> Self {
+        Self {
+            version,
+            ..self
+        }
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_owned(), value.to_owned());
+
+        self
+    }
+
+    pub fn body(self, body: Vec<u8>) -> Self {
+        Self {
+            body: Some(body),
+            ..self
+        }
+    }
+
+    /// Fails with InvalidStartLine if the method or target weren't specified or InvalidTarget
+    /// if the target doesn't parse.
+    pub fn build(self) -> Result<Request> {
+        let target = self.target.ok_or(Error::InvalidStartLine)?;
+
+        Ok(Request {
+            start_line: StartLine {
+                method: self.method.ok_or(Error::InvalidStartLine)?,
+                target: Target::parse(&target)?,
+                version: self.version,
+            },
+            headers: Headers::new(self.headers),
+            body: self.body,
+            head_len: 0,
+            leftover: vec![],
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use async_std::io::{Cursor};
     
-    use futures::executor::LocalPool;
+    use futures::{executor::LocalPool, io::AsyncWriteExt};
 
     use super::*;
+    use crate::test_support::TestTransport;
+
+    #[test]
+    pub fn method_round_trips_through_str() {
+        let methods = vec![
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::HEAD,
+            Method::DELETE,
+            Method::CONNECT,
+            Method::OPTIONS,
+            Method::TRACE,
+            Method::PATCH,
+        ];
+
+        for m in methods {
+            assert_eq!(format!("{}", m), m.as_str());
+            assert_eq!(Method::parse(m.as_str()), Ok(m));
+        }
+    }
 
     #[test]
     pub fn can_parse_start_line() {
@@ -380,6 +1008,51 @@ mod test {
         assert_eq!(start_line.target, Target::Authority(Authority { domain: "horse.billy".to_owned(), port: None }));
     }
 
+    #[test]
+    pub fn can_parse_ipv6_authority() {
+        let target = Target::parse("[::1]:443").unwrap();
+
+        assert_eq!(target, Target::Authority(Authority { domain: "::1".to_owned(), port: Some(443) }));
+        assert_eq!(format!("{}", target), "[::1]:443");
+
+        let target = Target::parse("[2001:db8::1]").unwrap();
+
+        assert_eq!(target, Target::Authority(Authority { domain: "2001:db8::1".to_owned(), port: None }));
+
+        assert_eq!(Target::parse("[::1"), Err(Error::InvalidTarget));
+        assert_eq!(Target::parse("[horse]:443"), Err(Error::InvalidTarget));
+        assert_eq!(Target::parse("[::1]:"), Err(Error::InvalidTarget));
+        assert_eq!(Target::parse("[::1]443"), Err(Error::InvalidTarget));
+    }
+
+    #[test]
+    pub fn rejects_targets_in_the_wrong_form_for_the_method() {
+        assert_eq!(StartLine::parse("CONNECT /horse HTTP/1.1"), Err(Error::InvalidTarget));
+        assert_eq!(StartLine::parse("CONNECT http://horse.billy/ HTTP/1.1"), Err(Error::InvalidTarget));
+        assert_eq!(StartLine::parse("GET horse.billy:80 HTTP/1.1"), Err(Error::InvalidTarget));
+        assert_eq!(StartLine::parse("GET * HTTP/1.1"), Err(Error::InvalidTarget));
+
+        assert!(StartLine::parse("OPTIONS * HTTP/1.1").is_ok());
+        assert!(StartLine::parse("GET http://horse.billy/ HTTP/1.1").is_ok());
+    }
+
+    #[test]
+    pub fn can_parse_start_line_with_extra_spaces() {
+        let start_line = StartLine::parse("GET   /   HTTP/1.1").unwrap();
+
+        assert_eq!(start_line.method, Method::GET);
+        assert_eq!(start_line.target, Target::Path("/".to_owned()));
+        assert_eq!(start_line.version, HttpVersion::Http1_1);
+
+        let start_line = StartLine::parse("  GET / HTTP/1.1 ").unwrap();
+
+        assert_eq!(start_line.method, Method::GET);
+
+        assert_eq!(StartLine::parse("GET / HTTP/1.1 extra").unwrap_err(), Error::InvalidStartLine);
+        assert_eq!(StartLine::parse("GET /").unwrap_err(), Error::InvalidStartLine);
+        assert!(StartLine::parse("GET\t/ HTTP/1.1").is_err());
+    }
+
     #[test]
     pub fn can_parse_header() {
         let header = Headers::parse_header(":");
@@ -422,7 +1095,10 @@ mod test {
                 target: Target::Authority(Authority { domain: "horse.billy.com".to_owned(), port: Some(443) }),
                 version: HttpVersion::Http1_1,
             },
-            headers: Headers { headers }
+            headers: Headers { headers },
+            body: None,
+            head_len: 0,
+            leftover: vec![],
         };
 
         let data = vec![0u8; 0];
@@ -442,4 +1118,422 @@ mod test {
 
         assert_eq!(expected, std::str::from_utf8(&stream.into_inner()).unwrap());
     }
+
+    #[test]
+    pub fn can_build_request() {
+        let built = RequestBuilder::new()
+            .method(Method::CONNECT)
+            .target("horse.billy.com:443")
+            .header("Host", "horse.billy.com:443")
+            .build()
+            .unwrap();
+
+        let mut headers = HashMap::new();
+        headers.insert("Host".to_owned(), "horse.billy.com:443".to_owned());
+
+        let expected = Request {
+            start_line: StartLine {
+                method: Method::CONNECT,
+                target: Target::Authority(Authority { domain: "horse.billy.com".to_owned(), port: Some(443) }),
+                version: HttpVersion::Http1_1,
+            },
+            headers: Headers::new(headers),
+            body: None,
+            head_len: 0,
+            leftover: vec![],
+        };
+
+        assert_eq!(built, expected);
+        assert_eq!(RequestBuilder::new().target("/").build(), Err(Error::InvalidStartLine));
+    }
+
+    #[test]
+    pub fn can_round_trip_request_with_body() {
+        let request = RequestBuilder::new()
+            .method(Method::POST)
+            .target("/upload")
+            .header("Content-Length", "1000")
+            .body(b"horse".to_vec())
+            .build()
+            .unwrap();
+
+        let mut written = Cursor::new(vec![]);
+
+        let parsed = LocalPool::default().run_until(async {
+            request.write_to_stream(&mut written).await.unwrap();
+
+            let mut stream = Cursor::new(written.into_inner());
+
+            let mut parsed = Request::parse(&mut stream, &ParseOptions::default()).await.unwrap();
+            parsed.read_body(&mut stream, &ParseOptions::default()).await.unwrap();
+
+            parsed
+        });
+
+        assert_eq!(parsed.start_line, request.start_line);
+        assert_eq!(parsed.headers.get("Content-Length").unwrap(), "5");
+        assert_eq!(parsed.body, Some(b"horse".to_vec()));
+    }
+
+    #[test]
+    pub fn requests_without_body_write_no_content_length() {
+        let request = RequestBuilder::new()
+            .method(Method::GET)
+            .target("/")
+            .build()
+            .unwrap();
+
+        let mut written = Cursor::new(vec![]);
+
+        LocalPool::default().run_until(async {
+            request.write_to_stream(&mut written).await.unwrap();
+        });
+
+        assert_eq!(std::str::from_utf8(&written.into_inner()).unwrap(), "GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    pub fn enforces_max_total_request_len() {
+        // 45 bytes of start line and headers followed by a 40 byte body.
+        let request_str = format!("{}{}{}{}",
+            "POST /upload HTTP/1.1\r\n",
+            "Content-Length: 40\r\n",
+            "\r\n",
+            "a".repeat(40),
+        );
+
+        let options = ParseOptions::default().with_max_total_request_len(60);
+
+        let result = LocalPool::default().run_until(async {
+            let mut stream = Cursor::new(request_str.as_bytes());
+
+            let mut parsed = Request::parse(&mut stream, &options).await.unwrap();
+            parsed.read_body(&mut stream, &options).await
+        });
+
+        assert_eq!(result, Err(Error::RequestTooLarge));
+
+        let options = ParseOptions::default().with_max_total_request_len(20);
+
+        let result = LocalPool::default().run_until(async {
+            Request::parse(Cursor::new(request_str.as_bytes()), &options).await
+        });
+
+        assert_eq!(result.unwrap_err(), Error::RequestTooLarge);
+    }
+
+    #[test]
+    pub fn can_parse_request_ending_at_eof() {
+        let parse = |request: &'static str| LocalPool::default().run_until(async move {
+            Request::parse(Cursor::new(request.as_bytes()), &ParseOptions::default()).await
+        });
+
+        let parsed = parse("GET / HTTP/1.1\r\nHost: horse.billy\r\nheader1: horse").unwrap();
+
+        assert_eq!(parsed.start_line.target, Target::Path("/".to_owned()));
+        assert_eq!(parsed.headers.get("Host").unwrap(), "horse.billy");
+        assert_eq!(parsed.headers.get("header1").unwrap(), "horse");
+
+        let parsed = parse("GET / HTTP/1.1\r\nHost: horse.billy\r\n").unwrap();
+
+        assert_eq!(parsed.headers.get("Host").unwrap(), "horse.billy");
+
+        assert_eq!(parse("GET / HTTP/1.1").unwrap_err(), Error::UnexpectedEndOfStream);
+        assert_eq!(parse("").unwrap_err(), Error::UnexpectedEndOfStream);
+    }
+
+    #[test]
+    pub fn empty_header_values_are_optional() {
+        let request_str = "GET / HTTP/1.1\r\nX-Empty:\r\n\r\n";
+
+        let parse = |options: ParseOptions| LocalPool::default().run_until(async move {
+            Request::parse(Cursor::new(request_str.as_bytes()), &options).await
+        });
+
+        assert_eq!(parse(ParseOptions::default()).unwrap_err(), Error::InvalidHeader);
+
+        let parsed = parse(ParseOptions::default().with_allow_empty_header_values(true)).unwrap();
+
+        assert_eq!(parsed.headers.get("X-Empty").unwrap(), "");
+    }
+
+    #[test]
+    pub fn keeps_bytes_read_past_the_headers() {
+        let request_str = "CONNECT horse.billy:443 HTTP/1.1\r\n\r\n\x16\x03\x01";
+
+        let mut parsed = LocalPool::default().run_until(async {
+            Request::parse(Cursor::new(request_str.as_bytes()), &ParseOptions::default()).await.unwrap()
+        });
+
+        assert_eq!(parsed.leftover(), b"\x16\x03\x01");
+        assert_eq!(parsed.take_leftover(), b"\x16\x03\x01".to_vec());
+        assert_eq!(parsed.leftover(), b"");
+    }
+
+    #[test]
+    pub fn header_phase_budget_covers_the_whole_head() {
+        let (mut client, server) = TestTransport::pair();
+        let options = ParseOptions::default().with_max_header_phase(Duration::from_millis(50));
+
+        let result = async_std::task::block_on(async {
+            // Every byte arrives well within the budget, but all of them together don't.
+            let trickle = async {
+                for byte in b"GET / HTTP/1.1\r\nHost: horse.billy\r\n\r\n" {
+                    async_std::task::sleep(Duration::from_millis(10)).await;
+
+                    if client.write_all(&[*byte]).await.is_err() {
+                        break;
+                    }
+                }
+            };
+
+            futures::future::join(Request::parse(server, &options), trickle).await.0
+        });
+
+        assert_eq!(result, Err(Error::Timeout));
+    }
+
+    #[test]
+    pub fn rejects_overlong_targets_before_matching() {
+        let target = format!("{}.com", "a".repeat(MAX_TARGET_LEN));
+
+        assert_eq!(Target::parse(&target), Err(Error::InvalidTarget));
+        assert!(Target::parse("api.giphy.com:443").is_ok());
+    }
+
+    #[test]
+    pub fn rejects_overlong_start_line_without_reading_further() {
+        let options = ParseOptions::default();
+        let (mut client, server) = TestTransport::pair();
+
+        // One byte over the limit and no line ending. The client never sends anything else, so
+        // this only finishes if parse gives up on the byte that crosses the limit.
+        let start_line = format!("GET /{}", "a".repeat(options.max_start_line_len() - "GET /".len() + 1));
+
+        let result = LocalPool::default().run_until(async {
+            client.write_all(start_line.as_bytes()).await.unwrap();
+
+            Request::parse(server, &options).await
+        });
+
+        assert_eq!(result, Err(Error::StartLineExceedsMaxLength));
+    }
+
+    fn parse_str(request: &str) -> Result<Request> {
+        LocalPool::default().run_until(async {
+            Request::parse(Cursor::new(request.as_bytes().to_owned()), &ParseOptions::default()).await
+        })
+    }
+
+    #[test]
+    pub fn start_line_limit_is_inclusive() {
+        let max_len = ParseOptions::default().max_start_line_len();
+        let start_line = |len: usize| format!("GET /{} HTTP/1.1", "a".repeat(len - "GET / HTTP/1.1".len()));
+
+        assert_eq!(start_line(max_len).len(), max_len);
+        assert!(parse_str(&format!("{}\r\n\r\n", start_line(max_len))).is_ok());
+        assert_eq!(
+            parse_str(&format!("{}\r\n\r\n", start_line(max_len + 1))).map(|_| ()),
+            Err(Error::StartLineExceedsMaxLength)
+        );
+    }
+
+    #[test]
+    pub fn header_limit_is_inclusive() {
+        let max_len = ParseOptions::default().max_header_len();
+        let header = |len: usize| format!("X-Horse: {}", "a".repeat(len - "X-Horse: ".len()));
+
+        assert_eq!(header(max_len).len(), max_len);
+        assert!(parse_str(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header(max_len))).is_ok());
+        assert_eq!(
+            parse_str(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header(max_len + 1))).map(|_| ()),
+            Err(Error::HeaderTooLong)
+        );
+    }
+
+    #[test]
+    pub fn can_read_query_params() {
+        let expected = vec![
+            ("q".to_owned(), "cats".to_owned()),
+            ("limit".to_owned(), "5".to_owned()),
+        ].into_iter().collect::<HashMap<_, _>>();
+
+        assert_eq!(Target::parse("/search?q=cats&limit=5").unwrap().query_params(), expected);
+        assert_eq!(Target::parse("https://api.giphy.com/search?q=cats&limit=5&q=dogs").unwrap().query_params(), expected);
+
+        assert_eq!(Target::parse("/search?q=grumpy%20cat").unwrap().query_params()["q"], "grumpy cat");
+        assert!(Target::parse("/search").unwrap().query_params().is_empty());
+        assert!(Target::parse("api.giphy.com:443").unwrap().query_params().is_empty());
+    }
+
+    #[test]
+    pub fn skips_blank_lines_before_the_start_line() {
+        let request = parse_str("\r\n\r\nGET /hello HTTP/1.1\r\nHost: horse.billy\r\n\r\n").unwrap();
+
+        assert_eq!(request.start_line.target, Target::Path("/hello".to_owned()));
+        assert_eq!(request.headers.get("Host").unwrap(), "horse.billy");
+    }
+
+    #[test]
+    pub fn limits_the_number_of_lines() {
+        let options = ParseOptions::default().with_max_header_count(2);
+
+        let parse = |request: &str| LocalPool::default().run_until(async {
+            Request::parse(Cursor::new(request.as_bytes().to_owned()), &options).await.map(|_| ())
+        });
+
+        assert_eq!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"), Ok(()));
+        assert_eq!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Err(Error::TooManyHeaders));
+        assert_eq!(parse(&format!("{}GET / HTTP/1.1\r\n\r\n", "\r\n".repeat(3))), Err(Error::TooManyHeaders));
+
+        // Ending the headers by closing the stream doesn't get a client an extra line.
+        assert_eq!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2"), Ok(()));
+        assert_eq!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3"), Err(Error::TooManyHeaders));
+    }
+
+    #[test]
+    pub fn rejects_non_ascii_bytes() {
+        for request in &[&b"GET /caf\xc3\xa9 HTTP/1.1\r\n\r\n"[..], &b"GET / HTTP/1.1\r\nX-Horse: \xff\r\n\r\n"[..]] {
+            let result = LocalPool::default().run_until(async {
+                Request::parse(Cursor::new(request.to_vec()), &ParseOptions::default()).await
+            });
+
+            assert_eq!(result, Err(Error::InvalidEncoding));
+        }
+    }
+
+    #[test]
+    pub fn stray_cr_is_rejected_unless_line_endings_are_lenient() {
+        let request = "GET / HTTP/1.1\r\nX-Odd: a\rb\r\nX-Trailing: c\r\r\n\r\n";
+
+        let parse = |options: ParseOptions| LocalPool::default().run_until(async {
+            Request::parse(Cursor::new(request.as_bytes().to_owned()), &options).await
+        });
+
+        assert_eq!(parse(ParseOptions::default()).map(|_| ()), Err(Error::UnexpectedCR));
+
+        let parsed = parse(ParseOptions::default().with_strict_line_endings(false)).unwrap();
+
+        assert_eq!(parsed.headers.get("X-Odd").unwrap(), "a\rb");
+        assert_eq!(parsed.headers.get("X-Trailing").unwrap(), "c");
+    }
+
+    #[test]
+    pub fn expects_body_when_the_headers_declare_one() {
+        let get = parse_str("GET /hello HTTP/1.1\r\nHost: horse.billy\r\n\r\n").unwrap();
+
+        assert!(!get.start_line.expects_body());
+        assert!(!get.expects_body());
+
+        let post = parse_str("POST /hello HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+
+        assert!(post.start_line.expects_body());
+        assert!(post.expects_body());
+
+        assert!(parse_str("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap().expects_body());
+        assert!(!parse_str("POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n").unwrap().expects_body());
+        assert!(parse_str("GET / HTTP/1.1\r\nContent-Length: horse\r\n\r\n").unwrap().expects_body());
+    }
+
+    /// Returns no bytes on its first read, then the whole of data, like an adapter that wrongly
+    /// reports EOF before more data arrives.
+    struct ZeroThenData {
+        data: Cursor<Vec<u8>>,
+        zeroed: bool,
+    }
+
+    impl futures::io::AsyncRead for ZeroThenData {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8]
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if !self.zeroed {
+                self.zeroed = true;
+                return std::task::Poll::Ready(Ok(0));
+            }
+
+            std::pin::Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[test]
+    pub fn a_zero_length_read_ends_the_stream() {
+        let mut stream = ZeroThenData {
+            data: Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            zeroed: false,
+        };
+
+        let result = LocalPool::default().run_until(async {
+            Request::parse(&mut stream, &ParseOptions::default()).await
+        });
+
+        assert_eq!(result.map(|_| ()), Err(Error::UnexpectedEndOfStream));
+
+        // The data after the zero read is left on the stream rather than consumed.
+        assert_eq!(stream.data.position(), 0);
+    }
+
+    #[test]
+    pub fn lf_only_requests_parse_like_crlf_ones() {
+        let crlf = "POST /upload?a=1 HTTP/1.1\r\nHost: horse.billy\r\nContent-Length: 5\r\nX-Horse: neigh\r\n\r\nhello";
+        let lf = crlf.replace("\r\n", "\n");
+
+        let parse = |request: String| LocalPool::default().run_until(async {
+            let mut data = Cursor::new(request.into_bytes());
+            let mut request = Request::parse(&mut data, &ParseOptions::default()).await.unwrap();
+            request.read_body(&mut data, &ParseOptions::default()).await.unwrap();
+
+            request
+        });
+
+        let from_crlf = parse(crlf.to_owned());
+        let from_lf = parse(lf);
+
+        assert_eq!(from_lf.start_line, from_crlf.start_line);
+        assert_eq!(from_lf.headers, from_crlf.headers);
+        assert_eq!(from_lf.headers.headers.len(), 3);
+        assert_eq!(from_lf.body.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(from_lf.body, from_crlf.body);
+        assert!(from_lf.leftover().is_empty());
+    }
+
+    #[test]
+    pub fn builds_connect_strings() {
+        let v4 = Authority { domain: "10.0.0.1".to_owned(), port: Some(443) };
+        let v6 = Authority { domain: "2001:db8::1".to_owned(), port: Some(443) };
+        let name = Authority { domain: "api.giphy.com".to_owned(), port: None };
+
+        assert_eq!(v4.to_connect_string(), Ok("10.0.0.1:443".to_owned()));
+        assert_eq!(v6.to_connect_string(), Ok("[2001:db8::1]:443".to_owned()));
+        assert_eq!(name.to_connect_string(), Err(Error::MissingPort));
+    }
+
+    #[test]
+    pub fn finds_the_effective_host() {
+        let host = |request: &str| parse_str(request).unwrap().host();
+        let authority = |domain: &str, port: Option<u16>| Some(Authority { domain: domain.to_owned(), port });
+
+        assert_eq!(
+            host("CONNECT API.giphy.com.:443 HTTP/1.1\r\nHost: other.com\r\n\r\n"),
+            authority("api.giphy.com", Some(443))
+        );
+        assert_eq!(host("GET /hello HTTP/1.1\r\nHost: Horse.Billy:8080\r\n\r\n"), authority("horse.billy", Some(8080)));
+        assert_eq!(host("GET /hello HTTP/1.1\r\nHost: [::1]\r\n\r\n"), authority("::1", None));
+        assert_eq!(host("GET /hello HTTP/1.1\r\nHost: localhost:8080\r\n\r\n"), authority("localhost", Some(8080)));
+        assert_eq!(host("GET /hello HTTP/1.1\r\nHost: myhost\r\n\r\n"), authority("myhost", None));
+        assert_eq!(host("GET /hello HTTP/1.1\r\nHost: my-host.example:\r\n\r\n"), None);
+        assert_eq!(
+            host("GET http://horse.billy/hello HTTP/1.1\r\nHost: other.com\r\n\r\n"),
+            authority("horse.billy", Some(80))
+        );
+        assert_eq!(host("GET /hello HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    pub fn only_http_1_1_requests_expect_continue() {
+        assert!(parse_str("POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n").unwrap().expects_continue());
+        assert!(!parse_str("POST / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").unwrap().expects_continue());
+        assert!(!parse_str("POST / HTTP/1.1\r\n\r\n").unwrap().expects_continue());
+    }
 }
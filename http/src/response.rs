@@ -1,109 +1,844 @@
-use async_std::io::Cursor;
+use async_std::io::{BufReader, Cursor, prelude::BufReadExt};
 use futures::{
     AsyncRead,
     AsyncReadExt,
+    AsyncWrite,
     AsyncWriteExt,
+    FutureExt,
 };
 
 use crate::{
     common::{
+        write_fully,
         HttpVersion,
         Headers
     },
-    error::Result
+    error::{Error, Result},
+    request::ParseOptions,
 };
+use log::warn;
 
 use std::collections::HashMap;
 
+/// How much of the body we read and write at a time unless told otherwise.
+pub const DEFAULT_BODY_CHUNK_SIZE: usize = 16 * 1024;
+
 pub struct Response {
     status: Status,
-    http_version: HttpVersion,
+
+    /// The reason phrase for the status line. Parsed responses keep whatever the server sent.
+    reason: String,
+
+    /// None until someone picks a version. The server fills it in from the request.
+    http_version: Option<HttpVersion>,
     headers: Headers,
     body: Box<dyn Send + Unpin + AsyncRead>,
+    compressible: bool,
+    body_chunk_size: usize,
 }
 
 impl Response {
+    /// Writes the response and its body to the stream. If the headers declare a Content-Length, we
+    /// write at most that many body bytes so the framing stays intact, warning if the body had
+    /// more. A body shorter than declared returns ContentLengthMismatch after writing so the caller
+    /// can drop the connection rather than leave the client hanging.
     pub async fn write_to_stream<S: Unpin + AsyncWriteExt>(mut self, mut s: S) -> Result<()> {
-        let ver = format!("{} ", self.http_version);
-        s.write(ver.as_bytes()).await?;
+        self.write_head(&mut s).await?;
+        self.write_body(&mut s).await?;
+
+        Ok(())
+    }
+
+    /// Writes the status line and headers. Unlike write_to_stream, this and write_body leave the
+    /// response around afterwards, e.g. for logging.
+    pub async fn write_head<S: Unpin + AsyncWriteExt>(&self, s: &mut S) -> Result<()> {
+        encode_head(self.http_version(), self.status.to_u16(), &self.reason, &self.headers, s).await
+    }
+
+    /// Streams the body to the stream and flushes it, returning the number of body bytes written.
+    /// This uses up the body. Checks the Content-Length like write_to_stream.
+    pub async fn write_body<S: Unpin + AsyncWriteExt>(&mut self, s: &mut S) -> Result<usize> {
+        encode_body(&self.headers, &mut self.body, self.body_chunk_size, s).await
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The version for the status line. HTTP/1.1 unless set otherwise.
+    pub fn http_version(&self) -> HttpVersion {
+        self.http_version.unwrap_or(HttpVersion::Http1_1)
+    }
+
+    /// How many bytes of the body to read and write at a time. Defaults to
+    /// DEFAULT_BODY_CHUNK_SIZE.
+    pub fn with_body_chunk_size(self, body_chunk_size: usize) -> Self {
+        Self {
+            body_chunk_size: body_chunk_size.max(1),
+            ..self
+        }
+    }
+
+    /// Sets the version explicitly, so the server won't change it to match the request.
+    pub fn with_http_version(self, http_version: HttpVersion) -> Self {
+        Self {
+            http_version: Some(http_version),
+            ..self
+        }
+    }
+
+    /// Answers in the request's version unless a version was already chosen. We don't speak
+    /// anything newer than HTTP/1.1.
+    pub(crate) fn default_http_version(&mut self, request_version: HttpVersion) {
+        if self.http_version.is_none() {
+            self.http_version = Some(match request_version {
+                HttpVersion::Http1_0 => HttpVersion::Http1_0,
+                _ => HttpVersion::Http1_1,
+            });
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The declared Content-Length. None if there isn't one or it doesn't parse.
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers.content_length().ok().flatten()
+    }
 
-        let status_code = format!("{} ", self.status.to_u16());
-        s.write(status_code.as_bytes()).await?;
+    pub fn new(status: Status, http_version: HttpVersion, headers: Headers, body: Box<dyn Send + Unpin + AsyncRead>) -> Self {
+        Self {
+            status,
+            reason: status.to_str().to_owned(),
+            http_version: Some(http_version),
+            headers,
+            body,
+            compressible: false,
+            body_chunk_size: DEFAULT_BODY_CHUNK_SIZE,
+        }
+    }
+
+    /// A response whose version gets decided when it's sent.
+    fn unversioned(status: Status, headers: Headers, body: Box<dyn Send + Unpin + AsyncRead>) -> Self {
+        Self {
+            http_version: None,
+            ..Response::new(status, HttpVersion::Http1_1, headers, body)
+        }
+    }
+
+    /// Parses a response's status line and headers from the stream. The rest of the stream becomes
+    /// the body. Any status code parses and the reason phrase is kept as sent, since servers don't
+    /// have to use the standard ones. Lines are bounded by the header limits in parse_options.
+    pub async fn parse<R>(data: R, parse_options: &ParseOptions) -> Result<Response>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let mut reader = BufReader::new(data);
+        let mut head_len = 0;
+
+        let status_line = read_line(&mut reader, parse_options.max_header_len()).await?;
+        head_len += status_line.len();
 
-        let status_message = format!("{}\r\n", self.status.to_str());
-        s.write(status_message.as_bytes()).await?;
+        let mut parts = status_line.splitn(3, ' ');
 
-        for (k, v) in self.headers.headers.iter() {
-            let header_line = format!("{}:{}\r\n", k, v);
-            s.write(header_line.as_bytes()).await?;
+        let http_version = HttpVersion::parse(parts.next().ok_or(Error::InvalidStartLine)?)?;
+        let code = parts.next().ok_or(Error::InvalidStartLine)?;
+
+        if code.len() != 3 || !code.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error::InvalidStartLine);
         }
 
-        s.write("\r\n".as_bytes()).await?;
+        let status = Status::from_u16(code.parse().map_err(|_| Error::InvalidStartLine)?);
+        let reason = parts.next().unwrap_or("").to_owned();
+
+        let mut headers = HashMap::new();
 
         loop {
-            let mut data: Vec<u8> = vec![0; 128];
+            let line = read_line(&mut reader, parse_options.max_header_len()).await?;
+            head_len += line.len();
 
-            let bytes_read = self.body.read(&mut data).await?;
+            if head_len > parse_options.max_headers_section_len() {
+                return Err(Error::HeadersSectionTooLong);
+            }
 
-            if bytes_read == 0 {
+            if line.is_empty() {
                 break;
             }
 
-            s.write(&data).await?;
+            let (key, value) = Headers::parse_header_with(&line, parse_options.allow_empty_header_values())?;
+            headers.insert(key.to_owned(), value.to_owned());
         }
 
-        Ok(())
+        Ok(Self {
+            status,
+            reason,
+            http_version: Some(http_version),
+            headers: Headers::new(headers),
+            body: Box::new(reader),
+            compressible: false,
+            body_chunk_size: DEFAULT_BODY_CHUNK_SIZE,
+        })
     }
 
-    pub fn new(status: Status, http_version: HttpVersion, headers: Headers, body: Box<dyn Send + Unpin + AsyncRead>) -> Self {
+    /// An informational (1xx) response. These have neither headers nor a body and precede the final
+    /// response on the same connection.
+    pub fn interim(status: Status) -> Response {
+        Response::unversioned(status, Headers::new(HashMap::new()), Box::new(async_std::io::empty()))
+    }
+
+    /// A response with no body, which says so with a Content-Length of 0. Statuses that can never
+    /// have a body, 1xx and 204, must not send a Content-Length, so they get none.
+    pub fn empty(status: Status) -> Response {
+        let mut headers = HashMap::new();
+
+        if !(status.is_informational() || status == Status::NoContent) {
+            headers.insert("Content-length".to_owned(), "0".to_owned());
+        }
+
+        Response::unversioned(status, Headers::new(headers), Box::new(async_std::io::empty()))
+    }
+
+    /// A 200 OK with no body.
+    pub fn ok() -> Response {
+        Response::empty(Status::Ok)
+    }
+
+    /// The 200 that opens a CONNECT tunnel. Everything after it is tunnel data rather than a body,
+    /// so it has no Content-Length, which RFC 7231 forbids here anyway.
+    pub fn connect_established() -> Response {
+        Response::unversioned(Status::Ok, Headers::new(HashMap::new()), Box::new(async_std::io::empty()))
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Sets a header, replacing any existing header with the same name.
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key, value);
+    }
+
+    /// Opts this response into compression. When built with the compression feature, the server
+    /// gzips the body of compressible responses if the client sent an Accept-Encoding allowing it.
+    pub fn compressible(self) -> Self {
         Self {
-            status,
-            http_version,
-            headers,
-            body
+            compressible: true,
+            ..self
         }
     }
 
+    pub fn is_compressible(&self) -> bool {
+        self.compressible
+    }
+
+    /// Reads the entire body, gzips it and updates the Content-Encoding and Content-Length headers
+    /// to match.
+    #[cfg(feature = "compression")]
+    pub async fn gzip(mut self) -> Result<Self> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut body = vec![];
+        self.body.read_to_end(&mut body).await?;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&body)?;
+        let compressed = encoder.finish()?;
+
+        self.headers.insert("Content-Encoding", "gzip");
+        self.headers.insert("Content-Length", &format!("{}", compressed.len()));
+
+        Ok(Self {
+            body: Box::new(Cursor::new(compressed)),
+            compressible: false,
+            ..self
+        })
+    }
+
     pub fn error_response(status: Status, message: &str) -> Response {
         let mut headers = HashMap::new();
         headers.insert("Content-length".to_owned(), format!("{}", message.len()));
     
-        Response::new(status, HttpVersion::Http1_1, Headers::new(headers), Box::new(Cursor::new(message.to_owned())))
+        Response::unversioned(status, Headers::new(headers), Box::new(Cursor::new(message.to_owned())))
+    }
+
+    /// Like error_response, but with a JSON body of the form
+    /// {"error":{"code":"...","message":"..."}} for API clients.
+    pub fn json_error(status: Status, code: &str, message: &str) -> Response {
+        let body = format!(r#"{{"error":{{"code":"{}","message":"{}"}}}}"#, json_escape(code), json_escape(message));
+
+        let mut response = Response::error_response(status, &body);
+        response.set_header("Content-Type", "application/json");
+
+        response
+    }
+
+    /// A 503 with no body, for when we're too busy. Sets Retry-After if given how many seconds the
+    /// client should wait.
+    pub fn service_unavailable(retry_after_secs: Option<u64>) -> Response {
+        let mut response = Response::empty(Status::ServiceUnavailable);
+
+        if let Some(secs) = retry_after_secs {
+            response.set_header("Retry-After", &secs.to_string());
+        }
+
+        response
+    }
+}
+
+/// Writes a response with the given parts to w, returning the number of body bytes written. This
+/// is what Response::write_to_stream does, for when there's no Response to hand, e.g. to write one
+/// into a buffer. The body is checked against any Content-Length the same way.
+pub async fn encode_response<W, B>(status: Status, version: HttpVersion, headers: &Headers, mut body: B, w: &mut W) -> Result<usize>
+    where W: AsyncWrite + Unpin,
+          B: AsyncRead + Unpin
+{
+    encode_head(version, status.to_u16(), status.to_str(), headers, w).await?;
+    encode_body(headers, &mut body, DEFAULT_BODY_CHUNK_SIZE, w).await
+}
+
+async fn encode_head<W>(version: HttpVersion, status: u16, reason: &str, headers: &Headers, s: &mut W) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    // Catch a bad Content-Length before anything goes out.
+    headers.content_length()?;
+
+    let ver = format!("{} ", version);
+    write_fully(s, ver.as_bytes()).await?;
+
+    let status_code = format!("{} ", status);
+    write_fully(s, status_code.as_bytes()).await?;
+
+    let status_message = format!("{}\r\n", reason);
+    write_fully(s, status_message.as_bytes()).await?;
+
+    for (k, v) in headers.headers.iter() {
+        let header_line = format!("{}:{}\r\n", k, v);
+        write_fully(s, header_line.as_bytes()).await?;
+    }
+
+    write_fully(s, b"\r\n").await?;
+
+    Ok(())
+}
+
+async fn encode_body<W, B>(headers: &Headers, body: &mut B, chunk_size: usize, s: &mut W) -> Result<usize>
+    where W: AsyncWrite + Unpin,
+          B: AsyncRead + Unpin
+{
+    let declared_len = headers.content_length()?;
+
+    let mut data: Vec<u8> = vec![0; chunk_size];
+    let mut body_len = 0;
+
+    loop {
+        // Anything past the declared length would be taken for the start of the next response.
+        let wanted = match declared_len {
+            Some(declared) => std::cmp::min(declared - body_len, data.len()),
+            None => data.len(),
+        };
+
+        if wanted == 0 {
+            break;
+        }
+
+        let bytes_read = body.read(&mut data[..wanted]).await?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        write_fully(s, &data[..bytes_read]).await?;
+        body_len += bytes_read;
+    }
+
+    s.flush().await?;
+
+    if let Some(declared) = declared_len {
+        if declared != body_len {
+            warn!("Response declared Content-Length {} but its body was {} bytes", declared, body_len);
+
+            return Err(Error::ContentLengthMismatch { declared, written: body_len });
+        }
+
+        // Only look for extra bytes that are already there, since a streaming body that never
+        // ends would otherwise hang us.
+        if let Some(Ok(extra)) = body.read(&mut data[..1]).now_or_never() {
+            if extra > 0 {
+                warn!("Response declared Content-Length {} but its body was longer. Sent only the declared length.", declared);
+            }
+        }
+    }
+
+    Ok(body_len)
+}
+
+/// Escapes a string for use inside a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Reads a line ending in LF or CRLF, without the line ending, failing if it's longer than max_len.
+async fn read_line<R>(reader: &mut BufReader<R>, max_len: usize) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = vec![];
+
+    // Leaves room for the CRLF so a line of exactly max_len still fits.
+    let read = reader.take(max_len as u64 + 2).read_until(b'\n', &mut line).await?;
+
+    if read == 0 {
+        return Err(Error::UnexpectedEndOfStream);
+    }
+
+    if line.last() != Some(&b'\n') {
+        return Err(if read > max_len { Error::HeaderTooLong } else { Error::UnexpectedEndOfStream });
+    }
+
+    line.pop();
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
     }
+
+    if line.len() > max_len {
+        return Err(Error::HeaderTooLong);
+    }
+
+    String::from_utf8(line).map_err(|_| Error::InvalidEncoding)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Status {
+    Continue,
     Ok,
+    NoContent,
     BadRequest,
+    Forbidden,
     MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    UpgradeRequired,
     RequestHeaderFieldsTooLarge,
     UriTooLong,
+    NotImplemented,
     BadGateway,
+    ServiceUnavailable,
 
-    // TODO: Other status codes
+    /// Any code we don't have a name for.
+    Other(u16),
 }
 
 impl Status {
     pub fn to_u16(&self) -> u16 {
         match self {
+            Self::Continue => 100,
             Self::Ok => 200,
+            Self::NoContent => 204,
             Self::MethodNotAllowed => 405,
             Self::BadRequest => 400,
+            Self::Forbidden => 403,
+            Self::RequestTimeout => 408,
+            Self::PayloadTooLarge => 413,
+            Self::UpgradeRequired => 426,
             Self::RequestHeaderFieldsTooLarge => 431,
             Self::UriTooLong => 414,
+            Self::NotImplemented => 501,
             Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::Other(code) => *code,
+        }
+    }
+
+    /// The status for a code, falling back to Other for codes we don't know.
+    pub fn from_u16(code: u16) -> Status {
+        match code {
+            100 => Self::Continue,
+            200 => Self::Ok,
+            204 => Self::NoContent,
+            400 => Self::BadRequest,
+            403 => Self::Forbidden,
+            405 => Self::MethodNotAllowed,
+            408 => Self::RequestTimeout,
+            413 => Self::PayloadTooLarge,
+            414 => Self::UriTooLong,
+            426 => Self::UpgradeRequired,
+            431 => Self::RequestHeaderFieldsTooLarge,
+            501 => Self::NotImplemented,
+            502 => Self::BadGateway,
+            503 => Self::ServiceUnavailable,
+            code => Self::Other(code),
         }
     }
 
     pub fn to_str(&self) -> &str {
         match self {
+            Self::Continue => "Continue",
             Self::Ok => "OK",
+            Self::NoContent => "No Content",
             Self::MethodNotAllowed => "Method Not Allowed",
             Self::BadRequest => "Bad Request",
+            Self::Forbidden => "Forbidden",
+            Self::RequestTimeout => "Request Timeout",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::UpgradeRequired => "Upgrade Required",
             Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             Self::UriTooLong => "URI Too Long",
+            Self::NotImplemented => "Not Implemented",
             Self::BadGateway => "Bad Gateway",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::Other(_) => "Unknown",
+        }
+    }
+
+    /// 1xx
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.to_u16())
+    }
+
+    /// 2xx
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.to_u16())
+    }
+
+    /// 3xx
+    pub fn is_redirect(&self) -> bool {
+        (300..400).contains(&self.to_u16())
+    }
+
+    /// 4xx
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.to_u16())
+    }
+
+    /// 5xx
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.to_u16())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{
+        AsyncWrite,
+        executor::LocalPool,
+    };
+
+    use std::{
+        io,
+        iter::FromIterator,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// A writer that buffers everything and counts how often it was flushed.
+    #[derive(Default)]
+    struct FlushTracker {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for FlushTracker {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A writer that takes one byte per write and interrupts every other write, like a congested
+    /// socket that keeps getting signals.
+    #[derive(Default)]
+    struct TricklingWriter {
+        data: Vec<u8>,
+        interrupt: bool,
+    }
+
+    impl AsyncWrite for TricklingWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            self.interrupt = !self.interrupt;
+
+            if !self.interrupt {
+                return Poll::Ready(Err(io::ErrorKind::Interrupted.into()));
+            }
+
+            self.data.push(buf[0]);
+            Poll::Ready(Ok(1))
         }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    pub fn survives_short_and_interrupted_writes() {
+        let mut writer = TricklingWriter::default();
+
+        LocalPool::default().run_until(async {
+            Response::error_response(Status::Ok, "hello").write_to_stream(&mut writer).await.unwrap();
+        });
+
+        assert_eq!(writer.data, b"HTTP/1.1 200 OK\r\nContent-length:5\r\n\r\nhello");
+    }
+
+    #[test]
+    pub fn write_to_stream_flushes() {
+        let mut writer = FlushTracker::default();
+
+        LocalPool::default().run_until(async {
+            Response::error_response(Status::Ok, "hello").write_to_stream(&mut writer).await.unwrap();
+        });
+
+        assert_eq!(writer.flushes, 1);
+        assert!(writer.data.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    pub fn can_categorize_statuses() {
+        assert!(Status::Ok.is_success());
+        assert!(!Status::Ok.is_client_error());
+        assert!(Status::BadRequest.is_client_error());
+        assert!(Status::UriTooLong.is_client_error());
+        assert!(Status::BadGateway.is_server_error());
+        assert!(!Status::BadGateway.is_success());
+        assert!(!Status::NotImplemented.is_informational());
+        assert!(Status::Continue.is_informational());
+        assert!(!Status::NotImplemented.is_redirect());
+    }
+
+    #[test]
+    pub fn write_to_stream_detects_wrong_content_length() {
+        let mut headers = HashMap::new();
+        headers.insert("Content-length".to_owned(), "10".to_owned());
+
+        let response = Response::new(Status::Ok, HttpVersion::Http1_1, Headers::new(headers), Box::new(Cursor::new("hello")));
+
+        let mut writer = FlushTracker::default();
+
+        let result = LocalPool::default().run_until(async {
+            response.write_to_stream(&mut writer).await
+        });
+
+        assert_eq!(result, Err(Error::ContentLengthMismatch { declared: 10, written: 5 }));
+        assert!(writer.data.ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    pub fn empty_responses_have_zero_length_bodies() {
+        let mut writer = FlushTracker::default();
+
+        LocalPool::default().run_until(async {
+            Response::empty(Status::BadGateway).write_to_stream(&mut writer).await.unwrap();
+        });
+
+        assert!(writer.data.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(writer.data.ends_with(b"Content-length:0\r\n\r\n"));
+
+        let mut writer = FlushTracker::default();
+
+        LocalPool::default().run_until(async {
+            Response::ok().write_to_stream(&mut writer).await.unwrap();
+        });
+
+        assert!(writer.data.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(writer.data.ends_with(b"Content-length:0\r\n\r\n"));
+    }
+
+    #[test]
+    pub fn response_is_still_usable_after_borrowing_write() {
+        let mut response = Response::error_response(Status::BadGateway, "nope");
+        let mut writer = FlushTracker::default();
+
+        let written = LocalPool::default().run_until(async {
+            response.write_head(&mut writer).await.unwrap();
+            response.write_body(&mut writer).await.unwrap()
+        });
+
+        log::info!("Sent {} with a {} byte body", response.status().to_u16(), written);
+
+        assert_eq!(response.status().to_u16(), 502);
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "4");
+        assert_eq!(written, 4);
+        assert!(writer.data.ends_with(b"\r\n\r\nnope"));
+    }
+
+    #[test]
+    pub fn parse_keeps_custom_reason_phrases() {
+        let data = Cursor::new("HTTP/1.1 200 Totally Fine\r\nContent-Length: 2\r\n\r\nhi".as_bytes());
+
+        let (response, body) = LocalPool::default().run_until(async {
+            let mut response = Response::parse(data, &ParseOptions::default()).await.unwrap();
+
+            let mut body = String::new();
+            response.body.read_to_string(&mut body).await.unwrap();
+
+            (response, body)
+        });
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.reason(), "Totally Fine");
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "2");
+        assert_eq!(body, "hi");
+    }
+
+    #[test]
+    pub fn parse_accepts_unknown_status_codes() {
+        let data = Cursor::new("HTTP/1.1 299 Weird\r\n\r\n".as_bytes());
+
+        let response = LocalPool::default().run_until(async {
+            Response::parse(data, &ParseOptions::default()).await.unwrap()
+        });
+
+        assert_eq!(response.status(), Status::Other(299));
+        assert_eq!(response.status().to_u16(), 299);
+        assert_eq!(response.reason(), "Weird");
+    }
+
+    #[test]
+    pub fn parse_rejects_malformed_status_lines() {
+        let parse = |data: &'static str| LocalPool::default().run_until(async move {
+            Response::parse(Cursor::new(data.as_bytes()), &ParseOptions::default()).await.map(|_| ())
+        });
+
+        assert_eq!(parse("HTTP/1.1 2000 OK\r\n\r\n"), Err(Error::InvalidStartLine));
+        assert_eq!(parse("HTTP/1.1\r\n\r\n"), Err(Error::InvalidStartLine));
+        assert_eq!(parse("HTTP/9 200 OK\r\n\r\n"), Err(Error::InvalidHttpVersion));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn headers_set_after_construction_are_written() {
+        let mut response = Response::ok();
+        response.set_header("Via", "1.1 giphy_proxy");
+        response.headers_mut().insert("date", "Fri, 16 Oct 2026 00:00:00 GMT");
+
+        let mut written = Cursor::new(vec![]);
+        LocalPool::default().run_until(response.write_to_stream(&mut written)).unwrap();
+
+        let written = String::from_utf8(written.into_inner()).unwrap();
+
+        assert!(written.contains("\r\nVia:1.1 giphy_proxy\r\n"));
+        assert!(written.contains("\r\ndate:Fri, 16 Oct 2026 00:00:00 GMT\r\n"));
+    }
+
+    #[test]
+    pub fn exposes_the_declared_content_length() {
+        assert_eq!(Response::error_response(Status::Ok, "horse").content_length(), Some(5));
+        assert_eq!(Response::interim(Status::Continue).content_length(), None);
+    }
+
+    #[test]
+    pub fn service_unavailable_sets_retry_after_when_given() {
+        let response = Response::service_unavailable(Some(30));
+
+        assert_eq!(response.status().to_u16(), 503);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+
+        let response = Response::service_unavailable(None);
+
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.headers().get("Retry-After"), None);
+    }
+
+    #[test]
+    pub fn json_errors_are_json() {
+        let mut response = Response::json_error(Status::BadRequest, "bad_target", "Target \"nope\" isn't allowed");
+
+        let expected = r#"{"error":{"code":"bad_target","message":"Target \"nope\" isn't allowed"}}"#;
+
+        let mut body = String::new();
+        LocalPool::default().run_until(response.body.read_to_string(&mut body)).unwrap();
+
+        assert_eq!(body, expected);
+        assert_eq!(response.content_length(), Some(expected.len()));
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "application/json");
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    pub fn never_writes_past_the_declared_length() {
+        let headers = Headers::from_iter(vec![("Content-Length".to_owned(), "5".to_owned())]);
+        let mut response = Response::new(Status::Ok, HttpVersion::Http1_1, headers, Box::new(Cursor::new(b"hello world".to_vec())));
+        let mut writer = FlushTracker::default();
+
+        let written = LocalPool::default().run_until(async {
+            response.write_head(&mut writer).await.unwrap();
+            response.write_body(&mut writer).await.unwrap()
+        });
+
+        assert_eq!(written, 5);
+        assert!(writer.data.ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    pub fn encodes_a_response_without_a_response() {
+        let headers = Headers::from_iter(vec![("Content-Length".to_owned(), "5".to_owned())]);
+        let mut out = vec![];
+
+        let written = LocalPool::default().run_until(async {
+            encode_response(Status::Ok, HttpVersion::Http1_0, &headers, Cursor::new("hello"), &mut out).await.unwrap()
+        });
+
+        assert_eq!(written, 5);
+        assert_eq!(out, b"HTTP/1.0 200 OK\r\nContent-Length:5\r\n\r\nhello");
+    }
+
+    #[test]
+    pub fn content_length_presence_matches_the_kind_of_response() {
+        let write = |response: Response| {
+            let mut writer = FlushTracker::default();
+
+            LocalPool::default().run_until(async {
+                response.write_to_stream(&mut writer).await.unwrap();
+            });
+
+            String::from_utf8(writer.data).unwrap()
+        };
+
+        assert_eq!(write(Response::connect_established()), "HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(
+            write(Response::error_response(Status::BadRequest, "nope")),
+            "HTTP/1.1 400 Bad Request\r\nContent-length:4\r\n\r\nnope"
+        );
+        assert_eq!(write(Response::ok()), "HTTP/1.1 200 OK\r\nContent-length:0\r\n\r\n");
+        assert_eq!(write(Response::empty(Status::NoContent)), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+}
@@ -1,29 +1,72 @@
 use async_std::net::{TcpListener, TcpStream, SocketAddr};
-use log::{debug, error};
+use log::{debug, error, info, Level};
 use futures::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
     Future,
-    channel::oneshot::{Sender},
-    stream::{StreamExt},
+    channel::oneshot::{self, Receiver, Sender},
+    future,
+    io::Cursor,
+    stream::{self, StreamExt},
 };
 
+use socket2::{Domain, Protocol, Socket, Type};
+
 use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::request::{ParseOptions, Request};
+use crate::common::{should_keep_alive, Headers, HttpVersion};
+use crate::counting_stream::CountingStream;
+use crate::request::{Method, ParseOptions, Request};
 use crate::response::{Response, Status};
 use crate::error::{Error, Result};
+use crate::rate_limit::log_rate_limited;
+
+/// The Server header we send unless told otherwise.
+const DEFAULT_SERVER_HEADER: &str = concat!("giphy_proxy/", env!("CARGO_PKG_VERSION"));
+
+/// std's listen backlog, which is what we used before it was configurable.
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// How long open connections get to finish after shutdown before we close them.
+const DEFAULT_DRAIN_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How many requests we serve on one connection before closing it, unless told otherwise.
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
 
 pub struct HttpServerBuilder {
     parse_options: ParseOptions,
-    bind_addr: Option<SocketAddr>,
-    notify_start: Option<Sender<()>>,
+    bind_addrs: Vec<SocketAddr>,
+    notify_start: Option<Sender<Vec<SocketAddr>>>,
+    server_header: Option<String>,
+    shutdown_signal: Option<Receiver<()>>,
+    drain_deadline: Duration,
+    reuse_addr: bool,
+    backlog: i32,
+    allow_trace: bool,
+    first_byte_timeout: Option<Duration>,
+    max_requests_per_connection: Option<usize>,
 }
 
 impl HttpServerBuilder {
     pub fn new() -> Self {
         Self {
             parse_options: ParseOptions::default(),
-            bind_addr: None,
+            bind_addrs: vec![],
             notify_start: None,
+            server_header: Some(DEFAULT_SERVER_HEADER.to_owned()),
+            shutdown_signal: None,
+            drain_deadline: DEFAULT_DRAIN_DEADLINE,
+            // std sets SO_REUSEADDR on everything but Windows, where it means something else.
+            reuse_addr: !cfg!(windows),
+            backlog: DEFAULT_BACKLOG,
+            allow_trace: false,
+            first_byte_timeout: None,
+            max_requests_per_connection: Some(DEFAULT_MAX_REQUESTS_PER_CONNECTION),
         }
     }
 
@@ -35,46 +78,224 @@ impl HttpServerBuilder {
         }
     }
 
-    pub fn bind_addr(self, addr: SocketAddr) -> Self {
+    /// Adds an address to listen on. Call this once per address to serve several, e.g. both IPv4
+    /// and IPv6.
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addrs.push(addr);
+        self
+    }
+
+    /// Once the server is listening, it sends the addresses it's bound to on this channel. These
+    /// have the actual ports when binding port 0.
+    pub fn notify_start(self, sender: Sender<Vec<SocketAddr>>) -> Self {
         Self {
-            bind_addr: Some(addr),
+            notify_start: Some(sender),
             ..self
         }
     }
 
-    pub fn notify_start(self, sender: Sender<()>) -> Self {
+    /// The Server header to put on every response. None suppresses the header entirely, even if a
+    /// handler sets one. Defaults to giphy_proxy/<version>.
+    pub fn server_header(self, header: Option<String>) -> Self {
         Self {
-            notify_start: Some(sender),
+            server_header: header,
+            ..self
+        }
+    }
+
+    /// Stop accepting connections once a value is sent on this channel. Dropping the sender without
+    /// sending doesn't shut the server down.
+    pub fn shutdown_signal(self, signal: Receiver<()>) -> Self {
+        Self {
+            shutdown_signal: Some(signal),
+            ..self
+        }
+    }
+
+    /// How long connections still open at shutdown get to finish before they're closed and `run`
+    /// returns. Tunnels can stay open indefinitely, so we can't wait on them forever. Defaults to
+    /// 30 seconds.
+    pub fn drain_deadline(self, deadline: Duration) -> Self {
+        Self {
+            drain_deadline: deadline,
+            ..self
+        }
+    }
+
+    /// Whether to set SO_REUSEADDR on the listener, so a restarted server can bind while old
+    /// connections are in TIME_WAIT.
+    pub fn reuse_addr(self, reuse_addr: bool) -> Self {
+        Self {
+            reuse_addr,
+            ..self
+        }
+    }
+
+    /// How many pending connections the OS queues for us before refusing more. Defaults to 128.
+    pub fn backlog(self, backlog: i32) -> Self {
+        Self {
+            backlog,
+            ..self
+        }
+    }
+
+    /// Whether to answer TRACE requests by echoing the request back. Off by default, in which case
+    /// they get a 405. Either way TRACE never reaches the handler.
+    pub fn allow_trace(self, allow_trace: bool) -> Self {
+        Self {
+            allow_trace,
+            ..self
+        }
+    }
+
+    /// How long a connection may sit without sending the first byte of a request, whether it was
+    /// just accepted or is idle between requests, before we close it. None, the default, waits
+    /// forever. ParseOptions::with_max_header_phase bounds things once the first byte arrives.
+    pub fn first_byte_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            first_byte_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// How many requests a client may send on one keep-alive connection. The response to the last
+    /// one carries Connection: close and the connection is closed after it. None allows any number.
+    /// Defaults to 100.
+    pub fn max_requests_per_connection(self, max: Option<usize>) -> Self {
+        Self {
+            max_requests_per_connection: max,
             ..self
         }
     }
 
     pub fn build(self) -> Result<HttpServer> {
         Ok(HttpServer {
-            parse_options: self.parse_options,
-            bind_addr: self.bind_addr.ok_or(Error::NoBindAddress)?,
+            connection_options: ConnectionOptions {
+                parse_options: self.parse_options,
+                server_header: self.server_header,
+                allow_trace: self.allow_trace,
+                first_byte_timeout: self.first_byte_timeout,
+                max_requests_per_connection: self.max_requests_per_connection,
+            },
+            bind_addrs: match self.bind_addrs.len() {
+                0 => return Err(Error::NoBindAddress),
+                _ => self.bind_addrs,
+            },
             notify_start: Cell::from(self.notify_start),
+            shutdown_signal: Cell::from(self.shutdown_signal),
+            drain_deadline: self.drain_deadline,
+            reuse_addr: self.reuse_addr,
+            backlog: self.backlog,
+            active_connections: Arc::new(AtomicUsize::new(0)),
         })
     }
 }
 
-pub struct HttpServer {
+/// Information about the connection a request arrived on, captured when it was accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnInfo {
+    peer_addr: Option<SocketAddr>,
+}
+
+impl ConnInfo {
+    pub fn new(peer_addr: Option<SocketAddr>) -> Self {
+        Self { peer_addr }
+    }
+
+    /// The client's address. None for transports that don't have one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+}
+
+/// Settings each connection is served with.
+#[derive(Clone)]
+struct ConnectionOptions {
     parse_options: ParseOptions,
-    bind_addr: SocketAddr,
-    notify_start: Cell<Option<Sender<()>>>,
+    server_header: Option<String>,
+    allow_trace: bool,
+    first_byte_timeout: Option<Duration>,
+    max_requests_per_connection: Option<usize>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            parse_options: ParseOptions::default(),
+            server_header: Some(DEFAULT_SERVER_HEADER.to_owned()),
+            allow_trace: false,
+            first_byte_timeout: None,
+            max_requests_per_connection: Some(DEFAULT_MAX_REQUESTS_PER_CONNECTION),
+        }
+    }
+}
+
+pub struct HttpServer {
+    connection_options: ConnectionOptions,
+    bind_addrs: Vec<SocketAddr>,
+    notify_start: Cell<Option<Sender<Vec<SocketAddr>>>>,
+    shutdown_signal: Cell<Option<Receiver<()>>>,
+    drain_deadline: Duration,
+    reuse_addr: bool,
+    backlog: i32,
+    active_connections: Arc<AtomicUsize>,
+}
+
+/// Counts a connection as active for as long as it's alive, including when its task is aborted.
+struct ActiveConnection(Arc<AtomicUsize>);
+
+impl ActiveConnection {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Aborts a connection's task when dropped, so connections still open at the drain deadline are
+/// closed rather than left running after `run` returns.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl HttpServer {
-    pub async fn run<Fut>(&self, handler: fn(Request, TcpStream) -> Fut) -> Result<()> 
-        where Fut: 'static + Send + Future<Output = Result<Response>>
+    /// The number of connections currently being served. Grab this before calling `run` to watch
+    /// it from elsewhere.
+    pub fn active_connections(&self) -> Arc<AtomicUsize> {
+        self.active_connections.clone()
+    }
+
+    /// Serves connections until shutdown. The handler is cloned for each connection, so state it
+    /// captures should be cheap to clone, e.g. behind an Arc.
+    pub async fn run<F, Fut>(&self, handler: F) -> Result<()>
+        where F: 'static + Send + Sync + Clone + Fn(Request, CountingStream<TcpStream>, ConnInfo) -> Fut,
+              Fut: 'static + Send + Future<Output = Result<Response>>
     {
-        let listener = TcpListener::bind(self.bind_addr).await?;
+        let listeners = self
+            .bind_addrs
+            .iter()
+            .map(|addr| bind_listener(*addr, self.reuse_addr, self.backlog).map(TcpListener::from))
+            .collect::<std::io::Result<Vec<_>>>()?;
 
         {
             let mut notify = self.notify_start.take();
 
+            let local_addrs = listeners
+                .iter()
+                .map(|l| l.local_addr())
+                .collect::<std::io::Result<Vec<_>>>()?;
+
             if let Some(s) = notify.take() {
-                match s.send(()) {
+                match s.send(local_addrs) {
                     Ok(()) => {},
                     Err(e) => {
                         error!("Failed to notify receiver that service started: {:?}", e);
@@ -82,63 +303,400 @@ impl HttpServer {
                 };
             }
         }
-        
-        let parse_options = self.parse_options.clone();
 
-        listener.incoming().for_each_concurrent(None, |conn| async move {
-            let stream = match conn {
-                Ok(s) => s,
-                Err(e) => {
-                    debug!("{:?}", e);
-                    return;
+        let shutdown_signal = self.shutdown_signal.take();
+        let (draining_tx, draining_rx) = oneshot::channel::<()>();
+
+        let shutdown = async move {
+            let signalled = match shutdown_signal {
+                Some(signal) => signal.await.is_ok(),
+                None => false,
+            };
+
+            if !signalled {
+                future::pending::<()>().await;
+            }
+
+            debug!("Shutting down, no longer accepting connections");
+            let _ = draining_tx.send(());
+        };
+
+        // The drain deadline only starts counting once we've stopped accepting connections.
+        let drain_deadline = self.drain_deadline;
+
+        let deadline = async move {
+            match draining_rx.await {
+                Ok(()) => async_std::task::sleep(drain_deadline).await,
+                Err(_) => future::pending::<()>().await,
+            }
+        };
+
+        // Accept from every listener at once. Shutting down ends the merged stream, so it stops all
+        // of them.
+        let incoming = stream::select_all(listeners.iter().map(|l| Box::pin(l.incoming())));
+
+        let serve = incoming.take_until(shutdown).for_each_concurrent(None, |conn| {
+            let options = self.connection_options.clone();
+            let handler = handler.clone();
+            let active_connections = self.active_connections.clone();
+
+            async move {
+                let stream = match conn {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log_rate_limited("accept", Level::Warn, format_args!("Failed to accept connection: {:?}", e));
+                        return;
+                    }
+                };
+
+                let info = ConnInfo::new(stream.peer_addr().ok());
+                let stream = CountingStream::new(stream);
+                let counts = stream.counts();
+
+                let active = ActiveConnection::new(active_connections);
+
+                let mut task = AbortOnDrop(tokio::spawn(async move {
+                    let _active = active;
+
+                    serve_connection(stream, info, handler, options).await
+                }));
+                let _ = (&mut task.0).await;
+
+                debug!(
+                    "Connection from {} closed after reading {} and writing {} bytes",
+                    info.peer_addr().map(|a| a.to_string()).unwrap_or_else(|| "-".to_owned()),
+                    counts.read(),
+                    counts.written()
+                );
+            }
+        });
+
+        // Dropping serve drops every connection's AbortOnDrop, closing whatever is still open.
+        if let future::Either::Right(_) = future::select(Box::pin(serve), Box::pin(deadline)).await {
+            debug!(
+                "Drain deadline passed, closing {} remaining connections",
+                self.active_connections.load(Ordering::SeqCst)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a listening socket with the given options, which std's TcpListener::bind doesn't let us
+/// set.
+fn bind_listener(addr: SocketAddr, reuse_addr: bool, backlog: i32) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    socket.set_reuse_address(reuse_addr)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Serves requests from the stream until either side wants the connection closed, then closes it.
+/// Each request is parsed, handed to the handler and answered with its response. Parse failures
+/// are answered with an appropriate error response. Generic over the stream so connection handling
+/// can be tested without a socket.
+async fn serve_connection<S, F, Fut>(stream: S, info: ConnInfo, handler: F, options: ConnectionOptions)
+    where S: AsyncRead + AsyncWrite + Clone + Unpin,
+          F: Fn(Request, S, ConnInfo) -> Fut,
+          Fut: Future<Output = Result<Response>>
+{
+    // Bytes of the next request that were read along with the previous one.
+    let mut pending = vec![];
+    let mut requests_served = 0;
+
+    loop {
+        // Wait for the request to start ourselves, so a connection that never sends anything
+        // doesn't hold on to a task and a socket forever.
+        if let (true, Some(timeout)) = (pending.is_empty(), options.first_byte_timeout) {
+            let mut first = [0u8; 1];
+
+            match async_std::future::timeout(timeout, stream.clone().read(&mut first)).await {
+                Ok(Ok(read)) => pending.extend_from_slice(&first[..read]),
+                Ok(Err(e)) => {
+                    debug!("Failed to read from connection: {}", e);
+                    break;
+                },
+                Err(_) => {
+                    debug!("Closing connection that sent nothing for {:?}", timeout);
+                    break;
                 }
-            };    
-
-            let _ = tokio::spawn(async move {
-                 match Request::parse(stream.clone(), &parse_options).await {
-                    Ok(req) => {
-                        let response = match handler(req, stream.clone()).await {
-                            Ok(res) => res,
-                            Err(e) => {
-                                debug!("{:?}", e);
-                                return;
-                            }
-                        };
-
-                        match response.write_to_stream(stream).await {
-                            Ok(_) => {},
-                            Err(e) => {
-                                debug!("{:?}", e);
-                                return;
-                            }
-                        };
+            }
+        }
+
+        let mut data = Cursor::new(std::mem::take(&mut pending)).chain(stream.clone());
+        let parsed = Request::parse(&mut data, &options.parse_options).await;
+        let started = Instant::now();
+        let mut request_line = None;
+
+        // Parsing may stop before using up everything left over from the last request.
+        let (unread, _) = data.into_inner();
+        let mut unread = unread.get_ref()[unread.position() as usize..].to_vec();
+
+        let (response, keep_alive) = match parsed {
+            Ok(mut req) => {
+                // Tunnels take over the connection entirely, and we can't read chunked bodies, so
+                // those are left to the handler along with the stream.
+                let is_connect = req.start_line.method == Method::CONNECT;
+                let is_chunked = req.headers.get("Transfer-Encoding").is_some();
+
+                // Let the client know to go ahead and send the body we're about to read. Tunnels
+                // have no body, and one we'll refuse anyway gets its error status straight away.
+                let body_acceptable = req.declared_body_len(&options.parse_options).is_ok();
+
+                if req.expects_continue() && !is_connect && body_acceptable {
+                    if let Err(e) = Response::interim(Status::Continue).write_to_stream(stream.clone()).await {
+                        debug!("Failed to send 100 Continue: {}", e);
+                    }
+                }
+
+                // Read Content-Length bodies ourselves so handlers find them on the request and we
+                // know where the next request starts.
+                let body_read = if !is_connect && !is_chunked {
+                    let mut body_data = Cursor::new(std::mem::take(&mut unread)).chain(stream.clone());
+                    let read = req.read_body(&mut body_data, &options.parse_options).await;
+
+                    let (rest, _) = body_data.into_inner();
+                    unread = rest.get_ref()[rest.position() as usize..].to_vec();
+
+                    read
+                } else {
+                    Ok(())
+                };
+
+                requests_served += 1;
+
+                let keep_alive = should_keep_alive(req.start_line.version, &req.headers)
+                    && options.max_requests_per_connection.map_or(true, |max| requests_served < max)
+                    && !is_chunked
+                    && !is_connect
+                    && body_read.is_ok();
+
+                // Whatever was read past this request belongs either to the next one or, when the
+                // handler takes over the connection, to the handler.
+                if keep_alive {
+                    pending = req.take_leftover();
+                    pending.extend_from_slice(&unread);
+                } else {
+                    req.extend_leftover(&std::mem::take(&mut unread));
+                }
+
+                let version = req.start_line.version;
+                request_line = Some(req.start_line.to_string());
+
+                #[cfg(feature = "compression")]
+                let accepts_gzip = req.headers.accepts_encoding("gzip");
+
+                let handled = match body_read {
+                    Err(e) => {
+                        debug!("Failed to read request body {:?}", e);
+                        Ok(parse_error_response(&e))
+                    },
+                    Ok(()) if req.start_line.method == Method::TRACE => trace_response(req, options.allow_trace).await,
+                    Ok(()) if !is_connect && requires_upgrade(&req) => Ok(upgrade_required_response()),
+                    Ok(()) => {
+                        if !is_connect && req.headers.get("Upgrade").is_some() {
+                            ignore_upgrade(&mut req.headers);
+                        }
+
+                        handler(req, stream.clone(), info).await
+                    },
+                };
+
+                let response = match handled {
+                    #[cfg(feature = "compression")]
+                    Ok(res) if accepts_gzip && res.is_compressible() => match res.gzip().await {
+                        Ok(res) => Some(res),
+                        Err(e) => {
+                            debug!("Failed to compress response: {:?}", e);
+                            None
+                        }
                     },
+                    Ok(res) => Some(res),
                     Err(e) => {
-                        debug!("Failed to parse HTTP request {:?}", e);
-
-                        let response = match e {
-                            Error::HeadersSectionTooLong => Response::error_response(Status::RequestHeaderFieldsTooLarge, "Headers too long."),
-                            Error::HeaderTooLong => Response::error_response(Status::RequestHeaderFieldsTooLarge, "A header is too long."),
-                            Error::StartLineExceedsMaxLength => Response::error_response(Status::UriTooLong, "The target in the start line is too long."),
-                            _ => Response::error_response(Status::BadRequest, &format!("{}", e))
-                        };
-
-                        match response.write_to_stream(stream).await {
-                            Ok(_) => {},
-                            Err(e) => {
-                                debug!("Failed to send response: {}", e);
-                                return;
-                            }
-                        };
+                        debug!("{:?}", e);
+                        None
+                    }
+                };
 
-                        return;
+                match response {
+                    Some(mut res) => {
+                        res.default_http_version(version);
+
+                        // Without a length the client can only find the end of the body by us closing.
+                        // A 204 never has a body.
+                        let keep_alive = keep_alive
+                            && (res.content_length().is_some() || res.status() == Status::NoContent)
+                            && should_keep_alive(version, res.headers());
+
+                        if keep_alive && version == HttpVersion::Http1_0 {
+                            res.headers_mut().insert("Connection", "keep-alive");
+                        } else if !keep_alive {
+                            res.headers_mut().insert("Connection", "close");
+                        }
+
+                        (Some(res), keep_alive)
+                    },
+                    None => (None, false),
+                }
+            },
+            // The client hung up between requests.
+            Err(Error::UnexpectedEndOfStream) if requests_served > 0 => (None, false),
+            Err(e) => {
+                log_rate_limited("parse", Level::Debug, format_args!("Failed to parse HTTP request {:?}", e));
+                (Some(parse_error_response(&e)), false)
+            }
+        };
+
+        let mut written = false;
+
+        if let Some(mut response) = response {
+            match &options.server_header {
+                Some(server) => response.headers_mut().insert("Server", server),
+                None => {
+                    response.headers_mut().remove("Server");
+                }
+            };
+
+            let mut out = stream.clone();
+
+            let result = match response.write_head(&mut out).await {
+                Ok(()) => response.write_body(&mut out).await,
+                Err(e) => Err(e),
+            };
+
+            let body_len = match result {
+                Ok(len) => {
+                    written = true;
+                    len
+                },
+                Err(e) => {
+                    debug!("Failed to send response: {}", e);
+
+                    match e {
+                        Error::ContentLengthMismatch { written: len, .. } => len,
+                        _ => 0,
                     }
                 }
-            }).await;
-        }).await;
-        
-        Ok(())
+            };
+
+            log_access(info.peer_addr(), request_line.as_deref(), response.status(), body_len, started.elapsed(), None);
+        }
+
+        if !(keep_alive && written) {
+            break;
+        }
+    }
+
+    // Close our side once we're done rather than waiting for every clone of the stream to drop.
+    let mut stream = stream;
+
+    if let Err(e) = stream.close().await {
+        debug!("Failed to close connection: {}", e);
+    }
+}
+
+/// Writes one line to the "access" log target describing a completed request: the client's IP,
+/// the start line ("-" if it didn't parse), the status, body bytes sent and how long it took.
+/// Handlers that take over the connection, like tunnels, can add how many bytes they received.
+pub fn log_access(client: Option<SocketAddr>, request_line: Option<&str>, status: Status, bytes_sent: usize, elapsed: Duration, bytes_received: Option<usize>) {
+    info!(
+        target: "access",
+        "{} \"{}\" {} {} {}ms{}",
+        client.map(|c| c.ip().to_string()).unwrap_or_else(|| "-".to_owned()),
+        request_line.unwrap_or("-"),
+        status.to_u16(),
+        bytes_sent,
+        elapsed.as_millis(),
+        bytes_received.map(|r| format!(" received={}", r)).unwrap_or_default()
+    );
+}
+
+/// Answers a TRACE by echoing the request's start line and headers back as a message/http body,
+/// or with a 405 if TRACE isn't allowed. Credentials are left out of the echo so scripts can't use
+/// TRACE to read them.
+async fn trace_response(mut request: Request, allowed: bool) -> Result<Response> {
+    if !allowed {
+        return Ok(Response::error_response(Status::MethodNotAllowed, "TRACE is not allowed."));
+    }
+
+    for header in &["Authorization", "Proxy-Authorization", "Cookie"] {
+        request.headers.remove(header);
     }
+
+    let mut echo = vec![];
+    request.write_to_stream(&mut echo).await?;
+
+    let mut response = Response::error_response(Status::Ok, &String::from_utf8_lossy(&echo));
+    response.set_header("Content-Type", "message/http");
+
+    Ok(response)
+}
+
+/// Whether the request can only be served over a protocol it's asking to upgrade to. A server may
+/// ignore an Upgrade header, but HTTP/1.0 requests and ones without a Host have no HTTP/1.1 request
+/// to fall back on unless HTTP/1.1 is among the protocols offered.
+fn requires_upgrade(req: &Request) -> bool {
+    let protocols = match req.headers.get("Upgrade") {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let offers_http_1_1 = protocols.split(',').any(|p| p.trim().eq_ignore_ascii_case("HTTP/1.1"));
+    let can_fall_back = req.start_line.version == HttpVersion::Http1_1 && req.headers.get("Host").is_some();
+
+    !offers_http_1_1 && !can_fall_back
+}
+
+/// Drops an upgrade we aren't going to do, along with the headers the Connection header names for
+/// it (e.g. HTTP2-Settings), so the handler sees the request as a plain HTTP/1.1 one. Close and
+/// keep-alive stay in the Connection header.
+fn ignore_upgrade(headers: &mut Headers) {
+    let (kept, named): (Vec<_>, Vec<_>) = headers
+        .connection_options()
+        .into_iter()
+        .map(|o| o.to_owned())
+        .partition(|o| o.eq_ignore_ascii_case("close") || o.eq_ignore_ascii_case("keep-alive"));
+
+    for header in named.iter().map(|h| h.as_str()).chain(std::iter::once("Upgrade")) {
+        headers.remove(header);
+    }
+
+    if kept.is_empty() {
+        headers.remove("Connection");
+    } else {
+        headers.insert("Connection", &kept.join(", "));
+    }
+}
+
+/// We only speak HTTP/1.1, so a client that can't be served without switching protocols, e.g. to
+/// h2c, is told so rather than having its request handled as if it hadn't asked.
+fn upgrade_required_response() -> Response {
+    let mut response = Response::error_response(Status::UpgradeRequired, "This server only speaks HTTP/1.1.");
+    response.set_header("Upgrade", "HTTP/1.1");
+    response.set_header("Connection", "Upgrade");
+
+    response
+}
+
+/// Maps a failure from Request::parse to the response we send the client.
+fn parse_error_response(e: &Error) -> Response {
+    let message = match e {
+        Error::HeadersSectionTooLong => "Headers too long.".to_owned(),
+        Error::HeaderTooLong => "A header is too long.".to_owned(),
+        Error::StartLineExceedsMaxLength => "The target in the start line is too long.".to_owned(),
+        Error::RequestTooLarge => "The request is too large.".to_owned(),
+        Error::BodyTooLong => "The request body is too long.".to_owned(),
+        Error::Timeout => "Took too long to send the request headers.".to_owned(),
+        Error::InvalidMethod(m) => format!("Method {} is not implemented.", m),
+        _ => format!("{}", e),
+    };
+
+    Response::error_response(e.to_status(), &message)
 }
 
 #[cfg(test)]
@@ -147,20 +705,22 @@ mod test {
     use crate::request::*;
     use crate::response::*;
     use crate::common::*;
+    use crate::test_support::TestTransport;
 
-    use async_std::{
-        net::ToSocketAddrs,
-        io::Cursor,
-    };
+    use async_std::io::Cursor;
     use futures::{
+        AsyncReadExt,
+        AsyncWriteExt,
         channel::oneshot,
+        executor::LocalPool,
     };
 
     use std::collections::HashMap;
+    use std::time::Instant;
 
     #[test]
     pub fn can_handle_get_requests() {
-        async fn handle_request(req: Request, _stream: TcpStream) -> Result<Response> {
+        async fn handle_request(req: Request, _stream: CountingStream<TcpStream>, _info: ConnInfo) -> Result<Response> {
             assert_eq!(req.start_line.method, Method::GET);
             assert_eq!(req.start_line.target, Target::Path("/".to_owned()));
 
@@ -177,47 +737,665 @@ mod test {
             ))
         }
 
+        let (addr, handle) = spawn_test_server(handle_request);
+
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap();
 
-        let addrs = 
-            runtime.block_on(async {
-                 "127.0.0.1:12345".to_socket_addrs().await
-            })
-            .unwrap()
-            .into_iter()
-            .next()
+        runtime.block_on(async {
+            let response = reqwest::get(&format!("http://{}", addr)).await.unwrap();
+
+            assert_eq!(response.status().as_u16(), 200);
+            assert_eq!(response.text().await.unwrap(), "Hello world.");
+        });
+
+        handle.shutdown();
+    }
+
+    /// Writes the request into one end of an in-memory transport and hangs up, serves the other end
+    /// and returns everything the server sent back.
+    fn serve_in_memory_bytes<Fut>(request: &str, handler: fn(Request, TestTransport, ConnInfo) -> Fut, options: ConnectionOptions) -> Vec<u8>
+        where Fut: Future<Output = Result<Response>>
+    {
+        let (mut client, server) = TestTransport::pair();
+
+        LocalPool::default().run_until(async {
+            client.write_all(request.as_bytes()).await.unwrap();
+            client.close().await.unwrap();
+
+            serve_connection(server, ConnInfo::new(None), handler, options).await;
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+
+            response
+        })
+    }
+
+    fn serve_in_memory<Fut>(request: &str, handler: fn(Request, TestTransport, ConnInfo) -> Fut) -> String
+        where Fut: Future<Output = Result<Response>>
+    {
+        String::from_utf8_lossy(&serve_in_memory_bytes(request, handler, ConnectionOptions::default())).into_owned()
+    }
+
+    async fn handle_hello(req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+        assert_eq!(req.start_line.method, Method::GET);
+        assert_eq!(req.start_line.target, Target::Path("/hello".to_owned()));
+
+        Ok(Response::error_response(Status::Ok, "hi"))
+    }
+
+    #[test]
+    pub fn can_serve_requests_in_memory() {
+        let response = serve_in_memory("GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-length:2\r\n"));
+    }
+
+    #[test]
+    pub fn responds_to_parse_errors_in_memory() {
+        let response = serve_in_memory("GET /hello HTTP/9.9\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("InvalidHttpVersion"));
+    }
+
+    #[test]
+    pub fn responds_to_unknown_methods_with_not_implemented() {
+        let response = serve_in_memory("FROBNICATE / HTTP/1.1\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(response.contains("FROBNICATE"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    pub fn gzips_compressible_responses() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        async fn handle_request(_req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            Ok(Response::error_response(Status::Ok, "hello hello hello hello").compressible())
+        }
+
+        let response = serve_in_memory_bytes(
+            "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+            handle_request,
+            ConnectionOptions::default()
+        );
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let (head, body) = response.split_at(split + 4);
+        let head = std::str::from_utf8(head).unwrap();
+
+        assert!(head.contains("Content-Encoding:gzip\r\n"));
+        assert!(head.contains(&format!("Content-Length:{}\r\n", body.len())));
+
+        let mut decompressed = String::new();
+        GzDecoder::new(body).read_to_string(&mut decompressed).unwrap();
+
+        assert_eq!(decompressed, "hello hello hello hello");
+    }
+
+    #[test]
+    pub fn adds_configured_server_header() {
+        let response = serve_in_memory("GET /hello HTTP/1.1\r\n\r\n", handle_hello);
+
+        assert!(response.contains(&format!("Server:giphy_proxy/{}\r\n", env!("CARGO_PKG_VERSION"))));
+
+        let options = ConnectionOptions {
+            server_header: Some("horse".to_owned()),
+            ..ConnectionOptions::default()
+        };
+
+        let response = String::from_utf8(serve_in_memory_bytes("GET /hello HTTP/1.1\r\n\r\n", handle_hello, options)).unwrap();
+
+        assert!(response.contains("Server:horse\r\n"));
+
+        async fn handle_request(_req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            let mut response = Response::ok();
+            response.headers_mut().insert("Server", "leaky");
+
+            Ok(response)
+        }
+
+        let options = ConnectionOptions {
+            server_header: None,
+            ..ConnectionOptions::default()
+        };
+
+        let response = String::from_utf8(serve_in_memory_bytes("GET / HTTP/1.1\r\n\r\n", handle_request, options)).unwrap();
+
+        assert!(!response.contains("Server:"));
+    }
+
+    #[test]
+    pub fn sends_continue_before_reading_the_body() {
+        async fn handle_request(mut req: Request, mut stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            assert!(req.expects_continue());
+
+            req.read_body(&mut stream, &ParseOptions::default()).await?;
+
+            assert_eq!(req.body, Some(b"horse".to_vec()));
+
+            Ok(Response::ok())
+        }
+
+        let response = serve_in_memory(
+            "POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhorse",
+            handle_request
+        );
+
+        assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    pub fn refuses_oversized_bodies_without_sending_continue() {
+        let request = format!(
+            "POST /hello HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            ParseOptions::default().max_body_len() + 1
+        );
+
+        let response = serve_in_memory(&request, handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(!response.contains("100 Continue"));
+    }
+
+    #[test]
+    pub fn serves_pipelined_requests_on_one_connection() {
+        let response = serve_in_memory(
+            "GET /hello HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+            handle_hello
+        );
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        // Only the last response announces the close.
+        assert_eq!(response.matches("Connection:close\r\n").count(), 1);
+    }
+
+    #[test]
+    pub fn closes_http_1_0_connections_by_default() {
+        let (mut client, server) = TestTransport::pair();
+
+        let response = LocalPool::default().run_until(async {
+            // We never hang up, so serve_connection only returns if it decides to close.
+            client.write_all(b"GET /hello HTTP/1.0\r\n\r\n").await.unwrap();
+
+            serve_connection(server, ConnInfo::new(None), handle_hello, ConnectionOptions::default()).await;
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            response
+        });
+
+        assert_eq!(response.matches(" 200 OK\r\n").count(), 1);
+    }
+
+    /// Stops a server started with spawn_test_server.
+    struct ShutdownHandle {
+        shutdown: oneshot::Sender<()>,
+        done: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl ShutdownHandle {
+        /// Signals shutdown and waits for `run` to return.
+        fn shutdown(self) {
+            self.shutdown.send(()).unwrap();
+            self.done.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    /// A server started by spawn_test_server_with.
+    struct TestServer {
+        addrs: Vec<SocketAddr>,
+        active_connections: Arc<AtomicUsize>,
+        handle: ShutdownHandle,
+    }
+
+    /// Builds the server and runs it with the handler in its own thread, returning once it's
+    /// listening. Bind the builder to port 0 so there's no port to pick and nothing to race.
+    fn spawn_test_server_with<F, Fut>(builder: HttpServerBuilder, handler: F) -> TestServer
+        where F: 'static + Send + Sync + Clone + Fn(Request, CountingStream<TcpStream>, ConnInfo) -> Fut,
+              Fut: 'static + Send + Future<Output = Result<Response>>
+    {
+        let (started_tx, started_rx) = oneshot::channel::<Vec<SocketAddr>>();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+
+        let server = builder
+            .notify_start(started_tx)
+            .shutdown_signal(shutdown_rx)
+            .build()
             .unwrap();
 
-        let (tx, rx) = oneshot::channel::<()>();
+        let active_connections = server.active_connections();
 
         std::thread::spawn(move || {
             let runtime = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap();
-            
+
             runtime.block_on(async {
-                    HttpServerBuilder::new()
-                        .bind_addr(addrs)
-                        .notify_start(tx)
-                        .build()
-                        .unwrap()
-                        .run(handle_request)
-                        .await
-                        .unwrap();
+                server.run(handler).await.unwrap();
             });
+
+            done_tx.send(()).unwrap();
         });
 
-        runtime.block_on(async {
-            rx.await.unwrap();
+        TestServer {
+            addrs: futures::executor::block_on(started_rx).unwrap(),
+            active_connections,
+            handle: ShutdownHandle { shutdown: shutdown_tx, done: done_rx },
+        }
+    }
 
-            let response = reqwest::get("http://localhost:12345").await.unwrap();
+    /// Runs a server with the handler on an ephemeral loopback port in its own thread.
+    fn spawn_test_server<F, Fut>(handler: F) -> (SocketAddr, ShutdownHandle)
+        where F: 'static + Send + Sync + Clone + Fn(Request, CountingStream<TcpStream>, ConnInfo) -> Fut,
+              Fut: 'static + Send + Future<Output = Result<Response>>
+    {
+        let server = spawn_test_server_with(HttpServerBuilder::new().bind_addr(local_ephemeral()), handler);
 
-            assert_eq!(response.status().as_u16(), 200);
-            assert_eq!(response.text().await.unwrap(), "Hello world.");
+        (server.addrs[0], server.handle)
+    }
+
+    fn local_ephemeral() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    pub fn spawned_test_servers_serve_and_shut_down() {
+        let (addr, handle) = spawn_test_server(|_req, _stream, _info| async { Ok(Response::error_response(Status::Ok, "spawned")) });
+
+        assert_ne!(addr.port(), 0);
+
+        async_std::task::block_on(async {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            assert!(response.ends_with("\r\n\r\nspawned"));
+        });
+
+        handle.shutdown();
+
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    pub fn handler_sees_peer_addr() {
+        async fn handle_request(_req: Request, _stream: CountingStream<TcpStream>, info: ConnInfo) -> Result<Response> {
+            Ok(Response::error_response(Status::Ok, &format!("{}", info.peer_addr().unwrap())))
+        }
+
+        let (addr, handle) = spawn_test_server(handle_request);
+
+        async_std::task::block_on(async {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            assert!(response.ends_with(&format!("\r\n\r\n{}", client.local_addr().unwrap())));
+        });
+
+        handle.shutdown();
+    }
+
+    #[test]
+    pub fn shutdown_closes_stuck_connections_after_drain_deadline() {
+        async fn handle_request(_req: Request, _stream: CountingStream<TcpStream>, _info: ConnInfo) -> Result<Response> {
+            // Behaves like a tunnel that never ends.
+            future::pending::<()>().await;
+
+            Ok(Response::ok())
+        }
+
+        let server = spawn_test_server_with(
+            HttpServerBuilder::new()
+                .bind_addr(local_ephemeral())
+                .drain_deadline(Duration::from_millis(100)),
+            handle_request
+        );
+
+        async_std::task::block_on(async {
+            let mut client = TcpStream::connect(server.addrs[0]).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+            // Give the server a moment to hand the request to the handler.
+            async_std::task::sleep(Duration::from_millis(50)).await;
+
+            let shutdown_at = Instant::now();
+            server.handle.shutdown();
+
+            assert!(shutdown_at.elapsed() >= Duration::from_millis(100));
+
+            // The stuck connection was closed rather than left open.
+            let mut response = vec![];
+            let _ = client.read_to_end(&mut response).await;
+            assert!(response.is_empty());
+        });
+    }
+
+    #[test]
+    pub fn can_rebind_immediately_with_reuse_addr() {
+        let listener = bind_listener(local_ephemeral(), true, DEFAULT_BACKLOG).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+
+        // Closing our end first leaves it in TIME_WAIT, which is what trips up a restart.
+        listener.set_nonblocking(false).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        drop(accepted);
+        drop(listener);
+
+        bind_listener(addr, true, DEFAULT_BACKLOG).unwrap();
+    }
+
+    #[test]
+    pub fn counts_active_connections() {
+        async fn handle_request(_req: Request, _stream: CountingStream<TcpStream>, _info: ConnInfo) -> Result<Response> {
+            Ok(Response::ok())
+        }
+
+        /// Waits for the counter to settle on the expected value.
+        async fn wait_for(active: &AtomicUsize, expected: usize) {
+            let started = Instant::now();
+
+            while active.load(Ordering::SeqCst) != expected {
+                assert!(started.elapsed() < Duration::from_secs(5), "expected {} active connections", expected);
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let server = spawn_test_server_with(HttpServerBuilder::new().bind_addr(local_ephemeral()), handle_request);
+
+        async_std::task::block_on(async {
+            // Connections that haven't sent a request yet are parked in parsing.
+            let mut clients = vec![];
+
+            for _ in 0..3 {
+                clients.push(TcpStream::connect(server.addrs[0]).await.unwrap());
+            }
+
+            wait_for(&server.active_connections, 3).await;
+
+            drop(clients);
+
+            wait_for(&server.active_connections, 0).await;
         });
+
+        server.handle.shutdown();
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn writes_an_access_log_line_per_request() {
+        crate::test_support::capture_logs();
+
+        async fn handle_request(_req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            Ok(Response::error_response(Status::Ok, "logged"))
+        }
+
+        serve_in_memory("GET /access-log HTTP/1.1\r\n\r\n", handle_request);
+
+        let logs = crate::test_support::captured_logs();
+        let line = logs.iter().find(|l| l.contains("/access-log")).expect("no access log line");
+
+        assert!(line.starts_with("access: - \"GET /access-log HTTP/1.1\" 200 6 "), "{}", line);
+        assert!(line.ends_with("ms"));
+    }
+
+    #[test]
+    pub fn responses_default_to_the_request_version() {
+        let response = serve_in_memory("GET /hello HTTP/1.0\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+
+        async fn handle_request(_req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            Ok(Response::ok().with_http_version(HttpVersion::Http1_1))
+        }
+
+        let response = serve_in_memory("GET / HTTP/1.0\r\n\r\n", handle_request);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = serve_in_memory("GET /hello HTTP/2.0\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    pub fn answers_overlong_start_lines_with_414_promptly() {
+        let (mut client, server) = TestTransport::pair();
+        let max_len = ParseOptions::default().max_start_line_len();
+        let start_line = format!("GET /{}", "a".repeat(max_len - "GET /".len() + 1));
+
+        let response = LocalPool::default().run_until(async {
+            // We don't hang up, so the server has to answer without waiting for more.
+            client.write_all(start_line.as_bytes()).await.unwrap();
+
+            serve_connection(server, ConnInfo::new(None), handle_hello, ConnectionOptions::default()).await;
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            response
+        });
+
+        assert!(response.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    }
+
+    #[test]
+    pub fn serves_every_bind_addr() {
+        async fn handle_request(_req: Request, _stream: CountingStream<TcpStream>, info: ConnInfo) -> Result<Response> {
+            Ok(Response::error_response(Status::Ok, &format!("{}", info.peer_addr().unwrap())))
+        }
+
+        let server = spawn_test_server_with(
+            HttpServerBuilder::new()
+                .bind_addr(local_ephemeral())
+                .bind_addr(local_ephemeral()),
+            handle_request
+        );
+        let addrs = server.addrs.clone();
+
+        assert_eq!(addrs.len(), 2);
+
+        async_std::task::block_on(async {
+            for addr in &addrs {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                client.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+
+                assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert!(response.ends_with(&format!("\r\n\r\n{}", client.local_addr().unwrap())));
+            }
+        });
+
+        // One shutdown signal stops both listeners.
+        server.handle.shutdown();
+
+        for addr in &addrs {
+            assert!(std::net::TcpStream::connect(addr).is_err());
+        }
+    }
+
+    #[test]
+    pub fn trace_echoes_the_request_when_allowed() {
+        let options = ConnectionOptions {
+            allow_trace: true,
+            ..ConnectionOptions::default()
+        };
+
+        let response = serve_in_memory_bytes(
+            "TRACE /echo HTTP/1.1\r\nX-Horse: billy\r\nCookie: secret\r\n\r\n",
+            handle_hello,
+            options
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type:message/http\r\n"));
+        assert!(response.contains("\r\n\r\nTRACE /echo HTTP/1.1\r\n"));
+        assert!(response.contains("\r\nX-Horse:billy\r\n"));
+        assert!(!response.contains("secret"));
+    }
+
+    #[test]
+    pub fn trace_is_refused_by_default() {
+        let response = serve_in_memory("TRACE /echo HTTP/1.1\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    pub fn closes_connections_that_never_send_anything() {
+        let (mut client, server) = TestTransport::pair();
+
+        let options = ConnectionOptions {
+            first_byte_timeout: Some(Duration::from_millis(50)),
+            ..ConnectionOptions::default()
+        };
+
+        let response = async_std::task::block_on(async {
+            let started = Instant::now();
+
+            // We connect but never write or hang up.
+            serve_connection(server, ConnInfo::new(None), handle_hello, options).await;
+
+            assert!(started.elapsed() >= Duration::from_millis(50));
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+
+            response
+        });
+
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    pub fn handlers_find_the_body_on_the_request() {
+        async fn handle_request(req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            let body = req.body.map(|b| String::from_utf8(b).unwrap()).unwrap_or_default();
+
+            Ok(Response::error_response(Status::Ok, &format!("got {}", body)))
+        }
+
+        // Reading the body tells us where the next request starts, so this one can follow it.
+        let response = serve_in_memory(
+            "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhorseGET /hello HTTP/1.1\r\n\r\n",
+            handle_request
+        );
+
+        assert!(response.contains("\r\n\r\ngot horse"));
+        assert!(response.ends_with("\r\n\r\ngot "));
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    }
+
+    #[test]
+    pub fn short_bodies_get_400() {
+        let response = serve_in_memory("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhorse", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    pub fn answers_upgrade_only_requests_with_426() {
+        let response = serve_in_memory(
+            "GET /hello HTTP/1.0\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+            handle_hello
+        );
+
+        assert!(response.starts_with("HTTP/1.0 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nUpgrade:HTTP/1.1\r\n"));
+        assert!(response.contains("\r\nConnection:Upgrade\r\n"));
+
+        let response = serve_in_memory("GET /hello HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    }
+
+    #[test]
+    pub fn serves_requests_whose_upgrade_we_ignore() {
+        async fn handle_request(req: Request, _stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            assert!(req.headers.get("Upgrade").is_none());
+            assert!(req.headers.get("HTTP2-Settings").is_none());
+            assert_eq!(req.headers.get("Connection").map(|c| c.as_str()), Some("close"));
+
+            Ok(Response::error_response(Status::Ok, "hi"))
+        }
+
+        let response = serve_in_memory(
+            "GET /hello HTTP/1.1\r\nHost: horse.billy\r\nConnection: Upgrade, HTTP2-Settings, close\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+            handle_request
+        );
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+
+        // HTTP/1.0 clients can fall back too, if they offer to.
+        let response = serve_in_memory("GET /hello HTTP/1.0\r\nUpgrade: h2c, HTTP/1.1\r\n\r\n", handle_hello);
+
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    }
+
+    #[test]
+    pub fn closes_connections_after_the_request_cap() {
+        let options = ConnectionOptions {
+            max_requests_per_connection: Some(2),
+            ..ConnectionOptions::default()
+        };
+
+        // Without the cap, all three would be answered.
+        let response = serve_in_memory_bytes(
+            &"GET /hello HTTP/1.1\r\n\r\n".repeat(3),
+            handle_hello,
+            options
+        );
+        let response = String::from_utf8_lossy(&response);
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert_eq!(response.matches("Connection:close\r\n").count(), 1);
+    }
+
+    #[test]
+    pub fn tunnels_get_every_byte_pipelined_after_connect() {
+        // Echoes the tunnel back, starting with what was read along with the CONNECT.
+        async fn handle_request(mut req: Request, mut stream: TestTransport, _info: ConnInfo) -> Result<Response> {
+            if req.start_line.method != Method::CONNECT {
+                return Ok(Response::error_response(Status::Ok, "hi"));
+            }
+
+            let mut tunneled = req.take_leftover();
+            stream.read_to_end(&mut tunneled).await?;
+
+            Response::connect_established().write_to_stream(&mut stream).await?;
+            stream.write_all(&tunneled).await?;
+
+            Err(Error::ConnectionClosed)
+        }
+
+        let tunnel_data = "t".repeat(2048);
+        let request = format!(
+            "GET /hello HTTP/1.1\r\n\r\nCONNECT api.giphy.com:443 HTTP/1.1\r\n\r\n{}",
+            tunnel_data
+        );
+
+        let response = serve_in_memory(&request, handle_request);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&format!("HTTP/1.1 200 OK\r\n\r\n{}", tunnel_data)));
+    }
+}
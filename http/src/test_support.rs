@@ -0,0 +1,164 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// One direction of a TestTransport.
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool,
+    reset: bool,
+    reader: Option<Waker>,
+}
+
+/// One end of an in-memory, full duplex byte stream. Bytes written to one end are read from the
+/// other, so requests and responses can be exercised without binding a port. Clones share the
+/// same end, just like cloning an async_std `TcpStream`.
+#[derive(Clone)]
+pub struct TestTransport {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+impl TestTransport {
+    /// Creates both ends of a connected transport.
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(Mutex::new(Pipe::default()));
+        let b = Arc::new(Mutex::new(Pipe::default()));
+
+        (
+            Self { incoming: a.clone(), outgoing: b.clone() },
+            Self { incoming: b, outgoing: a },
+        )
+    }
+
+    /// Aborts the connection like a TCP reset. Anything unread is dropped and every read or write
+    /// on the other end fails with ConnectionReset from then on.
+    pub fn reset(&self) {
+        for pipe in &[&self.incoming, &self.outgoing] {
+            let mut pipe = pipe.lock().unwrap();
+
+            pipe.data.clear();
+            pipe.reset = true;
+
+            if let Some(waker) = pipe.reader.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl AsyncRead for TestTransport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.incoming.lock().unwrap();
+
+        if pipe.reset {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+        }
+
+        if pipe.data.is_empty() {
+            // Closing the other end's write side signals EOF once everything has been read.
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = std::cmp::min(buf.len(), pipe.data.len());
+
+        for (dst, src) in buf.iter_mut().zip(pipe.data.drain(..len)) {
+            *dst = src;
+        }
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for TestTransport {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.outgoing.lock().unwrap();
+
+        if pipe.reset {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+        }
+
+        if pipe.closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+
+        pipe.data.extend(buf);
+
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.outgoing.lock().unwrap();
+
+        pipe.closed = true;
+
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+static CAPTURED_LOGS: Lazy<Mutex<Vec<(Level, String)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Keeps every log line as "target: message".
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED_LOGS.lock().unwrap().push((record.level(), format!("{}: {}", record.target(), record.args())));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger;
+
+/// Routes log output somewhere tests can read it back with captured_logs. There can only be one
+/// logger per process, so this stays in place for every test in the binary once called.
+pub fn capture_logs() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Everything logged since capture_logs was first called, by any test.
+pub fn captured_logs() -> Vec<String> {
+    CAPTURED_LOGS.lock().unwrap().iter().map(|(_, line)| line.clone()).collect()
+}
+
+/// The captured lines that were logged at level.
+pub fn captured_logs_at(level: Level) -> Vec<String> {
+    CAPTURED_LOGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(l, _)| *l == level)
+        .map(|(_, line)| line.clone())
+        .collect()
+}